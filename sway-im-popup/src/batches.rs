@@ -0,0 +1,54 @@
+// `--batch-sizes` logs how many events each dispatch handles, and warns when
+// one batch is much bigger than usual: a sign we fell behind the compositor,
+// which may go along with the redraw storm.
+
+// A batch is a spike if it's this many times the running average...
+const SPIKE_FACTOR: f64 = 4.0;
+// ...and has at least this many events, so 1 after 0s isn't one.
+const MIN_SPIKE: usize = 8;
+// How much each batch moves the average.
+const WEIGHT: f64 = 0.1;
+
+#[derive(Default)]
+pub struct Batches {
+    // The running average batch size, None before the first batch.
+    average: Option<f64>,
+}
+
+impl Batches {
+    // Counts a batch of `events`, returning the usual size if it's a spike.
+    pub fn record(&mut self, events: usize) -> Option<f64> {
+        let size = events as f64;
+        let Some(average) = self.average else {
+            self.average = Some(size);
+            return None;
+        };
+        self.average = Some(average + WEIGHT * (size - average));
+        (events >= MIN_SPIKE && size > SPIKE_FACTOR * average).then_some(average)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spikes_stand_out_from_the_average() {
+        let mut batches = Batches::default();
+        for _ in 0..5 {
+            assert_eq!(batches.record(2), None);
+        }
+        assert_eq!(batches.record(7), None);
+        // The average is now 2.5.
+        assert_eq!(batches.record(11), Some(2.5));
+        // Bigger batches raise the average, so it takes more to spike.
+        assert_eq!(batches.record(11), None);
+    }
+
+    #[test]
+    fn small_batches_are_never_spikes() {
+        let mut batches = Batches::default();
+        batches.record(1);
+        assert_eq!(batches.record(MIN_SPIKE - 1), None);
+    }
+}
@@ -0,0 +1,110 @@
+// App asks a Clock for the time rather than calling Instant::now() itself,
+// so its timing decisions (debouncing, heartbeats) can be driven by a fake
+// clock.
+
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    // Called as each event is handled.
+    fn tick(&self) {}
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// For --deterministic-time: a millisecond passes per event, and no other
+// time at all, so the same events give the same timings in every run.
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Cell<Duration>,
+}
+
+impl VirtualClock {
+    pub fn new(start: Instant) -> Self {
+        VirtualClock {
+            start,
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed.get()
+    }
+
+    fn tick(&self) {
+        self.elapsed
+            .set(self.elapsed.get() + Duration::from_millis(1));
+    }
+}
+
+// For tests: time only passes when advance() says so.
+#[cfg(test)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Cell<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        self.elapsed.set(self.elapsed.get() + by);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ticks a fresh clock through a run of events, noting the time at each.
+    fn run(start: Instant, events: usize) -> Vec<Duration> {
+        let clock = VirtualClock::new(start);
+        (0..events)
+            .map(|_| {
+                clock.tick();
+                clock.now() - start
+            })
+            .collect()
+    }
+
+    #[test]
+    fn virtual_time_is_the_same_in_every_run() {
+        let first = run(Instant::now(), 3);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(first, run(Instant::now(), 3));
+        assert_eq!(first, [1, 2, 3].map(Duration::from_millis).to_vec(),);
+    }
+
+    #[test]
+    fn virtual_time_only_moves_on_events() {
+        let clock = VirtualClock::new(Instant::now());
+        let before = clock.now();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(clock.now(), before);
+    }
+}
@@ -0,0 +1,64 @@
+// `--compare REFERENCE.json` draws offscreen and checks the result against a
+// buffer saved earlier with --dump-last-buffer, to catch drawing regressions.
+// No compositor is involved.
+
+use std::{error::Error, fs, path::Path, process};
+
+use crate::{config::Config, draw, draw::Layout, HEIGHT, WIDTH};
+
+pub fn main(config: &Config, reference: &Path) -> Result<(), Box<dyn Error>> {
+    let (expected_layout, expected) = draw::from_json(&fs::read_to_string(reference)?)?;
+    let layout = Layout::new(WIDTH, HEIGHT, config.stride);
+    let mut actual = vec![0; layout.stride * layout.height];
+    let fill = config.fill_color.map_or(draw::FILL, |c| c.0);
+    for frame in 0..config.compare_frames {
+        draw::draw_into(&mut actual, layout, config, frame, fill);
+    }
+    if let Some(path) = &config.dump_last_buffer {
+        fs::write(path, draw::to_json(&actual, layout))?;
+    }
+
+    if (layout.width, layout.height) != (expected_layout.width, expected_layout.height) {
+        println!("FAIL: drew {layout:?}, reference is {expected_layout:?}");
+        process::exit(1);
+    }
+    let actual = draw::unpadded(&actual, layout);
+    let mismatches = mismatched_pixels(&actual, &expected, config.compare_tolerance);
+    if mismatches > 0 {
+        println!(
+            "FAIL: {mismatches} pixels differ from {}",
+            reference.display()
+        );
+        process::exit(1);
+    }
+    println!("PASS");
+    Ok(())
+}
+
+// Counts pixels where any channel differs by more than `tolerance`.
+fn mismatched_pixels(a: &[u8], b: &[u8], tolerance: u8) -> usize {
+    a.chunks_exact(4)
+        .zip(b.chunks_exact(4))
+        .filter(|(a, b)| {
+            a.iter()
+                .zip(b.iter())
+                .any(|(a, b)| a.abs_diff(*b) > tolerance)
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_mismatched_pixels() {
+        let a = [10, 20, 30, 255, 10, 20, 30, 255];
+        assert_eq!(mismatched_pixels(&a, &a, 0), 0);
+        let mut b = a;
+        b[5] = 23;
+        assert_eq!(mismatched_pixels(&a, &b, 0), 1);
+        assert_eq!(mismatched_pixels(&a, &b, 2), 1);
+        assert_eq!(mismatched_pixels(&a, &b, 3), 0);
+    }
+}
@@ -0,0 +1,155 @@
+// `--compat` prints the protocol versions we bound and what they give us,
+// as a capability summary for bug reports. `--min-protocol` refuses to run
+// against compositors older than the bug report is about.
+
+use std::{fmt, str::FromStr};
+
+// The oldest version of each protocol we can work with.
+const MINIMUM: &[(&str, u32)] = &[("wl_compositor", 4)];
+
+// Optional features: protocol, the version introducing it, and its name.
+const FEATURES: &[(&str, u32, &str)] = &[
+    ("wl_compositor", 4, "damage_buffer"),
+    ("wl_compositor", 5, "surface.offset"),
+    ("wl_compositor", 6, "preferred buffer scale"),
+];
+
+pub fn print(versions: &[(&str, u32)]) {
+    for row in rows(versions) {
+        println!("{row}");
+    }
+}
+
+fn rows(versions: &[(&str, u32)]) -> Vec<String> {
+    versions
+        .iter()
+        .map(|&(name, version)| {
+            let minimum = MINIMUM
+                .iter()
+                .find(|&&(protocol, _)| protocol == name)
+                .map_or(1, |&(_, minimum)| minimum);
+            let mut row = format!("{name:<28} v{version} (need v{minimum})");
+            for &(_, since, feature) in FEATURES.iter().filter(|f| f.0 == name) {
+                let available = if version >= since { "yes" } else { "no" };
+                row += &format!(", {feature}: {available}");
+            }
+            row
+        })
+        .collect()
+}
+
+// Short names for --min-protocol, as well as full interface names.
+const ALIASES: &[(&str, &str)] = &[
+    ("input_method_v2", "zwp_input_method_manager_v2"),
+    ("virtual_keyboard_v1", "zwp_virtual_keyboard_manager_v1"),
+    ("compositor", "wl_compositor"),
+    ("seat", "wl_seat"),
+    ("shm", "wl_shm"),
+    ("output", "wl_output"),
+];
+
+// Parsed from e.g. "input_method_v2=1,compositor=4".
+#[derive(Clone, Debug, Default)]
+pub struct MinVersions(Vec<(String, u32)>);
+
+impl FromStr for MinVersions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut result = Vec::new();
+        for entry in s.split(',').filter(|entry| !entry.is_empty()) {
+            let (name, version) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected protocol=version, got {entry}"))?;
+            let version = version
+                .parse()
+                .map_err(|e| format!("bad version for {name}: {e}"))?;
+            let interface = ALIASES
+                .iter()
+                .find(|&&(alias, _)| alias == name)
+                .map_or(name, |&(_, interface)| interface);
+            result.push((interface.to_owned(), version));
+        }
+        Ok(MinVersions(result))
+    }
+}
+
+impl fmt::Display for MinVersions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entries: Vec<String> = self
+            .0
+            .iter()
+            .map(|(interface, version)| format!("{interface}={version}"))
+            .collect();
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+impl MinVersions {
+    // Checks the versions the compositor advertises.
+    pub fn check(&self, advertised: &[(String, u32)]) -> Result<(), String> {
+        for (interface, minimum) in &self.0 {
+            let version = advertised
+                .iter()
+                .filter(|(name, _)| name == interface)
+                .map(|&(_, version)| version)
+                .max();
+            match version {
+                Some(version) if version >= *minimum => {}
+                Some(version) => {
+                    return Err(format!(
+                        "compositor has {interface} v{version}, --min-protocol wants v{minimum}"
+                    ))
+                }
+                None => {
+                    return Err(format!(
+                        "compositor lacks {interface}, --min-protocol wants v{minimum}"
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_rows() {
+        assert_eq!(
+            rows(&[("wl_compositor", 5), ("wl_seat", 9)]),
+            [
+                format!(
+                    "{:<28} v5 (need v4), damage_buffer: yes, surface.offset: yes, preferred buffer scale: no",
+                    "wl_compositor"
+                ),
+                format!("{:<28} v9 (need v1)", "wl_seat"),
+            ]
+        );
+    }
+
+    #[test]
+    fn min_versions() {
+        let min: MinVersions = "input_method_v2=1,wl_seat=5".parse().unwrap();
+        assert_eq!(min.to_string(), "zwp_input_method_manager_v2=1,wl_seat=5");
+        let advertised = |seat| {
+            vec![
+                ("zwp_input_method_manager_v2".to_owned(), 1),
+                ("wl_seat".to_owned(), seat),
+            ]
+        };
+        assert_eq!(min.check(&advertised(7)), Ok(()));
+        assert_eq!(
+            min.check(&advertised(4)),
+            Err("compositor has wl_seat v4, --min-protocol wants v5".into())
+        );
+        assert_eq!(
+            min.check(&[]),
+            Err("compositor lacks zwp_input_method_manager_v2, --min-protocol wants v1".into())
+        );
+        assert!("wl_seat".parse::<MinVersions>().is_err());
+        assert!("wl_seat=x".parse::<MinVersions>().is_err());
+    }
+}
@@ -0,0 +1,963 @@
+// Command-line flags, for tweaking the demo while hunting the bug.
+
+use crate::{compat::MinVersions, draw::Color, keys::ScancodeMap, WIDTH};
+use std::{
+    ffi::OsString,
+    fmt::{self, Display},
+    fs,
+    num::NonZeroUsize,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub draw_pattern: DrawPattern,
+    // Round-trip each popup commit with wl_display.sync, and log when done.
+    pub sync_commits: bool,
+    // Key that tears everything down and exits (e.g. esc).
+    pub exit_key: Option<String>,
+    // Overlay the frame number, so each captured frame identifies itself.
+    pub frame_number: bool,
+    // Log the current state after this many seconds without any events.
+    pub heartbeat: Option<u64>,
+    // Make each popup's first commit a blank (--initial-color) buffer, so
+    // none starts from the last popup's pixels. Also --empty-first-commit,
+    // for compositors that want a buffer on a popup's first commit.
+    pub clear_on_open: bool,
+    // Write every protocol message sent or received to this file.
+    pub log_wire: Option<PathBuf>,
+    // Avoid the bug by giving each popup a brand new surface.
+    pub workaround: bool,
+    // Don't forward keys to the app, to isolate the popup lifecycle.
+    pub no_vk: bool,
+    // Shut down cleanly after this many seconds, so CI runs can't hang.
+    pub max_runtime: Option<u64>,
+    // Size the popup to match the text input rectangle the compositor sends.
+    pub fit_rectangle: bool,
+    // Ignore popup toggles this soon after the previous one (keys are still
+    // forwarded).
+    pub toggle_delay: Duration,
+    // On exit, write the last committed buffer here as JSON.
+    pub dump_last_buffer: Option<PathBuf>,
+    // Send this xkb keymap to the virtual keyboard at startup, so injected
+    // keys mean the same thing regardless of the user's layout.
+    pub keymap: Option<PathBuf>,
+    // Instead of connecting, draw offscreen and compare against this buffer
+    // (as written by --dump-last-buffer).
+    pub compare: Option<PathBuf>,
+    // How many frames to draw for --compare.
+    pub compare_frames: usize,
+    // How far each channel may be off before --compare fails.
+    pub compare_tolerance: u8,
+    // Warn if the compositor sends nothing for a new popup within this time.
+    pub map_timeout: Option<Duration>,
+    // Fail rather than run without a virtual keyboard.
+    pub require_vk: bool,
+    // Extra key names for flags that take keys.
+    pub scancode_map: ScancodeMap,
+    // Allocate a new buffer for every frame, rather than reusing one when the
+    // compositor has released it (--single-buffer-reuse, the default).
+    pub fresh_buffer_each_frame: bool,
+    // On exit, write a JSON summary of the run here.
+    pub report: Option<PathBuf>,
+    // Pad buffer rows to this many bytes, to test padded SHM buffers.
+    pub stride: Option<usize>,
+    // Use a different fill color for each popup.
+    pub color_cycle: bool,
+    // Run against a private nested sway, started and stopped by us.
+    pub launch_nested: bool,
+    // Bare commits (no attach or damage) to make after opening each popup,
+    // in case the duplication is tied to commit counts.
+    pub recommit: usize,
+    // Draw each popup once, without requesting frame callbacks.
+    pub no_frame_callback: bool,
+    // Commit this text to the app on every key press, to show the input
+    // method is working end to end.
+    pub commit_string_on_toggle: Option<String>,
+    // Instead of running, check our pixel format handling on a wl_shm buffer.
+    pub pixel_format_test: bool,
+    // While a frame is pending, queue popup toggles and apply their net
+    // effect in the frame callback: at most one show or hide per frame.
+    pub toggle_per_frame: bool,
+    // Print the protocol versions we can use, then exit.
+    pub compat: bool,
+    // This many frames after the second popup opens, tear down as if the
+    // compositor had deactivated us: the path that crashes sway.
+    pub deactivate_after: Option<usize>,
+    pub commit_mode: CommitMode,
+    // Rotate the popup's buffer, as a client on a rotated output might.
+    pub transform: Transform,
+    // On exit, check that every popup, surface and buffer we made is gone.
+    pub leak_check: bool,
+    // Wait this long before drawing the first popup (only), which is the
+    // one that renders correctly.
+    pub delay_first_draw: Option<Duration>,
+    // Log the key events we'd forward instead of sending them to the app.
+    pub vk_to_log: bool,
+    // Refuse to run if the compositor's protocols are older than this.
+    pub min_protocol: MinVersions,
+    // Keep the keyboard grab after deactivate. This violates the protocol,
+    // to stress the compositor's teardown (where the crash is).
+    pub sticky_grab: bool,
+    // If another input method has the seat, keep trying until it's free
+    // rather than giving up.
+    pub wait_for_slot: bool,
+    // What the buffer holds before the first draw, in case the compositor
+    // shows it early. If given, the first popup's first commit is this color,
+    // before the animation starts. Also what --clear-on-open commits.
+    // Defaults to the background color.
+    pub initial_color: Option<Color>,
+    pub hide_mode: HideMode,
+    // Log how long each event takes to handle.
+    pub trace_timing: bool,
+    // Fill colors for successive popups, one per line, instead of the
+    // built-in --color-cycle ones.
+    pub palette: Option<PathBuf>,
+    // From the first popup on, replace it with a new one every frame until
+    // the compositor fails or this many have been made, then exit.
+    pub probe_popup_limit: Option<usize>,
+    // Forward only key presses or only releases to the app.
+    pub forward_on: ForwardOn,
+    // Only this key toggles the popup (and the app doesn't see it). By
+    // default every key does.
+    pub toggle_key: Option<String>,
+    // Key that hides the popup, if it's shown (and isn't passed on).
+    pub cancel_key: Option<String>,
+    // Show the popup as soon as we're activated, like IMEs that always have
+    // a candidate window, rather than on the first key.
+    pub spawn_on_activate: bool,
+    // Cycle through a few buffers, redrawing and damaging only the rows that
+    // changed since each was last used.
+    pub buffer_age_emulation: bool,
+    // Once we have the keyboard grab, type this as if on the keyboard: each
+    // key goes down and up through the same path as real ones.
+    pub auto_type: Option<String>,
+    // Keep the --keymap one on the virtual keyboard, rather than passing on
+    // each keymap the compositor sends with the grab.
+    pub override_keymap: bool,
+    // Draw each popup's first frame as diagonal stripes rather than an
+    // animation frame, so recordings show exactly when it was mapped.
+    pub mark_first_frame: bool,
+    // Drive the demo from this script instead of the keyboard.
+    pub scenario: Option<PathBuf>,
+    // The color popups fill up with, unless they cycle through a palette.
+    pub fill_color: Option<Color>,
+    // How many frames it takes to fill each row.
+    pub frames_per_row: NonZeroUsize,
+    // Reload draw parameters from this file whenever it changes.
+    pub watch_file: Option<PathBuf>,
+    // Comma-separated candidates, each owning an equal band of the popup
+    // from the top: clicking one commits it. They aren't drawn.
+    pub click_candidates: Option<String>,
+    // Type by committing each key's character through the input method,
+    // rather than passing keys on: the app sees no key events at all.
+    pub ime_only: bool,
+    // Log the shm pool's size every this many frames, to catch it growing
+    // without bound.
+    pub pool_stats: Option<NonZeroUsize>,
+    // Time that only moves as events arrive, so logged timings (and
+    // --toggle-delay) don't vary from run to run.
+    pub deterministic_time: bool,
+    // Show the same content in an ordinary window too, for comparison.
+    pub mirror: bool,
+    // Only pass on this many key presses before keys start toggling the
+    // popup, to see whether earlier typing changes what happens.
+    pub warmup_keys: usize,
+    // Warn if the grab's keymap types differently from the --keymap.
+    pub check_keymap: bool,
+    // Draw the next frame when a wl_display.sync comes back, rather than on
+    // wl_surface.frame, to see whether frame callback timing matters.
+    pub sync_driven_draw: bool,
+    // Log the requests we make to do with the popup, the grab and text
+    // input, with their arguments. Unlike --log-wire, events aren't logged.
+    pub log_requests: bool,
+    // Once activated, toggle the popup on every turn of the event loop, no
+    // keys needed, until deactivated, --frames or --max-runtime.
+    pub stress: bool,
+    // Shut down cleanly once this many frames have been drawn.
+    pub frames: Option<usize>,
+    // Attach the same wl_buffer to every popup, even with --workaround's
+    // new surfaces, and never draw into it while the compositor holds it.
+    pub shared_buffer: bool,
+    // Regularly log how far each popup has filled compared to how far it
+    // should have by now, to put a number on the too-fast animation.
+    pub fill_drift: bool,
+    // Write the settings in effect here at startup, for --config.
+    pub save_config: Option<PathBuf>,
+    // Draw every frame as a plain rectangle of the fill color, so nothing
+    // changes from one frame to the next.
+    pub static_fill: bool,
+    // Log how many events each dispatch handles, warning about spikes.
+    pub batch_sizes: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            draw_pattern: Default::default(),
+            sync_commits: false,
+            exit_key: None,
+            frame_number: false,
+            heartbeat: None,
+            clear_on_open: false,
+            log_wire: None,
+            workaround: false,
+            no_vk: false,
+            max_runtime: None,
+            fit_rectangle: false,
+            toggle_delay: Duration::ZERO,
+            dump_last_buffer: None,
+            keymap: None,
+            compare: None,
+            compare_frames: 1,
+            compare_tolerance: 0,
+            map_timeout: None,
+            require_vk: false,
+            scancode_map: Default::default(),
+            fresh_buffer_each_frame: false,
+            report: None,
+            stride: None,
+            color_cycle: false,
+            launch_nested: false,
+            recommit: 0,
+            no_frame_callback: false,
+            commit_string_on_toggle: None,
+            pixel_format_test: false,
+            toggle_per_frame: false,
+            compat: false,
+            deactivate_after: None,
+            commit_mode: Default::default(),
+            transform: Default::default(),
+            leak_check: false,
+            delay_first_draw: None,
+            vk_to_log: false,
+            min_protocol: Default::default(),
+            sticky_grab: false,
+            wait_for_slot: false,
+            initial_color: None,
+            hide_mode: Default::default(),
+            trace_timing: false,
+            palette: None,
+            probe_popup_limit: None,
+            forward_on: Default::default(),
+            toggle_key: None,
+            cancel_key: None,
+            spawn_on_activate: false,
+            buffer_age_emulation: false,
+            auto_type: None,
+            override_keymap: false,
+            mark_first_frame: false,
+            scenario: None,
+            fill_color: None,
+            frames_per_row: NonZeroUsize::new(10).unwrap(),
+            watch_file: None,
+            click_candidates: None,
+            ime_only: false,
+            pool_stats: None,
+            deterministic_time: false,
+            mirror: false,
+            warmup_keys: 0,
+            check_keymap: false,
+            sync_driven_draw: false,
+            log_requests: false,
+            stress: false,
+            frames: None,
+            shared_buffer: false,
+            fill_drift: false,
+            save_config: None,
+            static_fill: false,
+            batch_sizes: false,
+        }
+    }
+}
+
+impl Config {
+    // Flags can also be set in the environment: IM_POPUP_SYNC_COMMITS=1 acts
+    // like --sync-commits, IM_POPUP_EXIT_KEY=1 like --exit-key=1.
+    // Command-line flags take precedence. Other variables are ignored, even
+    // if they aren't UTF-8.
+    pub fn resolve(
+        env: impl Iterator<Item = (OsString, OsString)>,
+        mut args: impl Iterator<Item = String>,
+    ) -> Result<Self, String> {
+        let mut config = Config::default();
+        for (name, value) in env {
+            let Some(suffix) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix("IM_POPUP_"))
+            else {
+                continue;
+            };
+            let value = value
+                .to_str()
+                .ok_or_else(|| format!("{}: {value:?} isn't UTF-8", name.to_string_lossy()))?;
+            let arg = format!("--{}={value}", suffix.to_lowercase().replace('_', "-"));
+            config
+                .set(&arg, &mut std::iter::empty())
+                .map_err(|e| format!("{}: {e}", name.to_string_lossy()))?;
+        }
+        while let Some(arg) = args.next() {
+            config.set(&arg, &mut args)?;
+        }
+        // Check key names now, rather than on first use.
+        for key in [&config.exit_key, &config.toggle_key, &config.cancel_key] {
+            config.keycode(key)?;
+        }
+        if let Some(stride) = config.stride {
+            if stride < WIDTH * 4 || stride % 4 != 0 {
+                return Err(format!(
+                    "bad --stride {stride}: must be a multiple of 4, at least {}",
+                    WIDTH * 4
+                ));
+            }
+        }
+        if let Some(text) = &config.auto_type {
+            config.scancode_map.keystrokes(text)?;
+        }
+        if config.deterministic_time && config.heartbeat.is_some() {
+            return Err("--heartbeat needs real time, not --deterministic-time".into());
+        }
+        if config.deterministic_time && config.trace_timing {
+            return Err("--trace-timing needs real time, not --deterministic-time".into());
+        }
+        if config.override_keymap && config.keymap.is_none() {
+            return Err("--override-keymap needs a --keymap to send instead".into());
+        }
+        if config.shared_buffer && (config.fresh_buffer_each_frame || config.buffer_age_emulation) {
+            return Err("--shared-buffer means always using the same buffer".into());
+        }
+        if config.check_keymap && config.keymap.is_none() {
+            return Err("--check-keymap needs a --keymap to check against".into());
+        }
+        Ok(config)
+    }
+
+    pub fn keycode(&self, key: &Option<String>) -> Result<Option<u32>, String> {
+        key.as_deref()
+            .map(|name| self.scancode_map.resolve(name))
+            .transpose()
+    }
+
+    // The resolved settings as flag names and values, e.g. ("exit-key", "esc").
+    // Options that aren't set are left out.
+    pub fn to_flags(&self) -> Vec<(&'static str, String)> {
+        let switch = |on: bool| Some(if on { "1" } else { "0" }.to_owned());
+        let path = |path: &Option<PathBuf>| path.as_ref().map(|path| path.display().to_string());
+        let millis = |duration: Duration| duration.as_millis().to_string();
+        let flags = [
+            ("draw-pattern", Some(self.draw_pattern.to_string())),
+            ("sync-commits", switch(self.sync_commits)),
+            ("exit-key", shown(&self.exit_key)),
+            ("frame-number", switch(self.frame_number)),
+            ("heartbeat", shown(&self.heartbeat)),
+            ("clear-on-open", switch(self.clear_on_open)),
+            ("log-wire", path(&self.log_wire)),
+            ("workaround", switch(self.workaround)),
+            ("no-vk", switch(self.no_vk)),
+            ("max-runtime", shown(&self.max_runtime)),
+            ("fit-rectangle", switch(self.fit_rectangle)),
+            ("toggle-delay", Some(millis(self.toggle_delay))),
+            ("dump-last-buffer", path(&self.dump_last_buffer)),
+            ("keymap", path(&self.keymap)),
+            ("compare", path(&self.compare)),
+            ("compare-frames", Some(self.compare_frames.to_string())),
+            (
+                "compare-tolerance",
+                Some(self.compare_tolerance.to_string()),
+            ),
+            ("map-timeout", self.map_timeout.map(millis)),
+            ("require-vk", switch(self.require_vk)),
+            ("scancode-map", Some(self.scancode_map.to_string())),
+            (
+                "fresh-buffer-each-frame",
+                switch(self.fresh_buffer_each_frame),
+            ),
+            ("report", path(&self.report)),
+            ("stride", shown(&self.stride)),
+            ("color-cycle", switch(self.color_cycle)),
+            ("launch-nested", switch(self.launch_nested)),
+            ("recommit", Some(self.recommit.to_string())),
+            ("no-frame-callback", switch(self.no_frame_callback)),
+            (
+                "commit-string-on-toggle",
+                shown(&self.commit_string_on_toggle),
+            ),
+            ("pixel-format-test", switch(self.pixel_format_test)),
+            ("toggle-per-frame", switch(self.toggle_per_frame)),
+            ("compat", switch(self.compat)),
+            ("deactivate-after", shown(&self.deactivate_after)),
+            ("commit-mode", Some(self.commit_mode.to_string())),
+            ("transform", Some(self.transform.to_string())),
+            ("leak-check", switch(self.leak_check)),
+            ("delay-first-draw", self.delay_first_draw.map(millis)),
+            ("vk-to-log", switch(self.vk_to_log)),
+            (
+                "min-protocol",
+                Some(self.min_protocol.to_string()).filter(|s| !s.is_empty()),
+            ),
+            ("sticky-grab", switch(self.sticky_grab)),
+            ("wait-for-slot", switch(self.wait_for_slot)),
+            ("initial-color", shown(&self.initial_color)),
+            ("hide-mode", Some(self.hide_mode.to_string())),
+            ("trace-timing", switch(self.trace_timing)),
+            ("palette", path(&self.palette)),
+            ("probe-popup-limit", shown(&self.probe_popup_limit)),
+            ("forward-on", Some(self.forward_on.to_string())),
+            ("toggle-key", shown(&self.toggle_key)),
+            ("cancel-key", shown(&self.cancel_key)),
+            ("spawn-on-activate", switch(self.spawn_on_activate)),
+            ("buffer-age-emulation", switch(self.buffer_age_emulation)),
+            ("auto-type", shown(&self.auto_type)),
+            ("override-keymap", switch(self.override_keymap)),
+            ("mark-first-frame", switch(self.mark_first_frame)),
+            ("scenario", path(&self.scenario)),
+            ("fill-color", shown(&self.fill_color)),
+            ("frames-per-row", Some(self.frames_per_row.to_string())),
+            ("watch-file", path(&self.watch_file)),
+            ("click-candidates", shown(&self.click_candidates)),
+            ("ime-only", switch(self.ime_only)),
+            ("pool-stats", shown(&self.pool_stats)),
+            ("deterministic-time", switch(self.deterministic_time)),
+            ("mirror", switch(self.mirror)),
+            ("warmup-keys", Some(self.warmup_keys.to_string())),
+            ("check-keymap", switch(self.check_keymap)),
+            ("sync-driven-draw", switch(self.sync_driven_draw)),
+            ("log-requests", switch(self.log_requests)),
+            ("stress", switch(self.stress)),
+            ("frames", shown(&self.frames)),
+            ("shared-buffer", switch(self.shared_buffer)),
+            ("fill-drift", switch(self.fill_drift)),
+            ("static-fill", switch(self.static_fill)),
+            ("batch-sizes", switch(self.batch_sizes)),
+        ];
+        flags
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+
+    // The resolved settings as --save-config writes and --config reads them:
+    // one --flag=value per line, with values escaped so each stays on its
+    // line.
+    pub fn to_file(&self) -> String {
+        self.to_flags()
+            .iter()
+            .map(|(name, value)| format!("--{name}={}\n", escape(value)))
+            .collect()
+    }
+
+    // Applies a --watch-file: TOML-style `name = value` lines, each naming a
+    // drawing flag. Parameters missing from the file keep their last value.
+    pub fn set_draw_params(&mut self, text: &str) -> Result<(), String> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected name = value", i + 1))?;
+            let (name, value) = (name.trim(), value.trim().trim_matches('"'));
+            if !DRAW_PARAMS.contains(&name) {
+                return Err(format!(
+                    "line {}: {name} can't be changed while running",
+                    i + 1
+                ));
+            }
+            self.set(&format!("--{name}={value}"), &mut std::iter::empty())
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+        }
+        Ok(())
+    }
+
+    // Applies one flag, either --flag=value or --flag followed by its value.
+    fn set(&mut self, arg: &str, args: &mut impl Iterator<Item = String>) -> Result<(), String> {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_owned())),
+            None => (arg, None),
+        };
+        let value = Value { flag, inline, args };
+        match flag {
+            "--draw-pattern" => self.draw_pattern = value.parse()?,
+            "--sync-commits" => self.sync_commits = value.switch()?,
+            "--exit-key" => self.exit_key = Some(value.parse()?),
+            "--frame-number" => self.frame_number = value.switch()?,
+            "--heartbeat" => self.heartbeat = Some(value.parse()?),
+            "--clear-on-open" | "--empty-first-commit" => self.clear_on_open = value.switch()?,
+            "--log-wire" => self.log_wire = Some(value.parse()?),
+            "--workaround" => self.workaround = value.switch()?,
+            "--no-vk" => self.no_vk = value.switch()?,
+            "--max-runtime" => self.max_runtime = Some(value.parse()?),
+            "--fit-rectangle" => self.fit_rectangle = value.switch()?,
+            "--toggle-delay" => self.toggle_delay = Duration::from_millis(value.parse()?),
+            "--dump-last-buffer" => self.dump_last_buffer = Some(value.parse()?),
+            "--keymap" => self.keymap = Some(value.parse()?),
+            "--compare" => self.compare = Some(value.parse()?),
+            "--compare-frames" => self.compare_frames = value.parse()?,
+            "--compare-tolerance" => self.compare_tolerance = value.parse()?,
+            "--map-timeout" => self.map_timeout = Some(Duration::from_millis(value.parse()?)),
+            "--require-vk" => self.require_vk = value.switch()?,
+            "--scancode-map" => self.scancode_map = value.parse()?,
+            "--fresh-buffer-each-frame" => self.fresh_buffer_each_frame = value.switch()?,
+            "--single-buffer-reuse" => self.fresh_buffer_each_frame = !value.switch()?,
+            "--report" => self.report = Some(value.parse()?),
+            "--stride" => self.stride = Some(value.parse()?),
+            "--color-cycle" => self.color_cycle = value.switch()?,
+            "--launch-nested" => self.launch_nested = value.switch()?,
+            "--recommit" => self.recommit = value.parse()?,
+            "--no-frame-callback" => self.no_frame_callback = value.switch()?,
+            "--commit-string-on-toggle" => self.commit_string_on_toggle = Some(value.parse()?),
+            "--pixel-format-test" => self.pixel_format_test = value.switch()?,
+            "--toggle-per-frame" => self.toggle_per_frame = value.switch()?,
+            "--compat" => self.compat = value.switch()?,
+            "--deactivate-after" => self.deactivate_after = Some(value.parse()?),
+            "--commit-mode" => self.commit_mode = value.parse()?,
+            "--transform" => self.transform = value.parse()?,
+            "--leak-check" => self.leak_check = value.switch()?,
+            "--delay-first-draw" => {
+                self.delay_first_draw = Some(Duration::from_millis(value.parse()?))
+            }
+            "--vk-to-log" => self.vk_to_log = value.switch()?,
+            "--min-protocol" => self.min_protocol = value.parse()?,
+            "--sticky-grab" => self.sticky_grab = value.switch()?,
+            "--wait-for-slot" => self.wait_for_slot = value.switch()?,
+            "--initial-color" => self.initial_color = Some(value.parse()?),
+            "--hide-mode" => self.hide_mode = value.parse()?,
+            "--trace-timing" => self.trace_timing = value.switch()?,
+            "--palette" => self.palette = Some(value.parse()?),
+            "--probe-popup-limit" => self.probe_popup_limit = Some(value.parse()?),
+            "--forward-on" => self.forward_on = value.parse()?,
+            "--toggle-key" => self.toggle_key = Some(value.parse()?),
+            "--cancel-key" => self.cancel_key = Some(value.parse()?),
+            "--spawn-on-activate" => self.spawn_on_activate = value.switch()?,
+            "--buffer-age-emulation" => self.buffer_age_emulation = value.switch()?,
+            "--auto-type" => self.auto_type = Some(value.parse()?),
+            "--override-keymap" => self.override_keymap = value.switch()?,
+            "--mark-first-frame" => self.mark_first_frame = value.switch()?,
+            "--scenario" => self.scenario = Some(value.parse()?),
+            "--fill-color" => self.fill_color = Some(value.parse()?),
+            "--frames-per-row" => self.frames_per_row = value.parse()?,
+            "--watch-file" => self.watch_file = Some(value.parse()?),
+            "--click-candidates" => self.click_candidates = Some(value.parse()?),
+            "--ime-only" => self.ime_only = value.switch()?,
+            "--pool-stats" => self.pool_stats = Some(value.parse()?),
+            "--deterministic-time" => self.deterministic_time = value.switch()?,
+            "--mirror" => self.mirror = value.switch()?,
+            "--warmup-keys" => self.warmup_keys = value.parse()?,
+            "--check-keymap" => self.check_keymap = value.switch()?,
+            "--sync-driven-draw" => self.sync_driven_draw = value.switch()?,
+            "--log-requests" => self.log_requests = value.switch()?,
+            "--stress" => self.stress = value.switch()?,
+            "--frames" => self.frames = Some(value.parse()?),
+            "--shared-buffer" => self.shared_buffer = value.switch()?,
+            "--fill-drift" => self.fill_drift = value.switch()?,
+            "--save-config" => self.save_config = Some(value.parse()?),
+            "--static-fill" => self.static_fill = value.switch()?,
+            "--batch-sizes" => self.batch_sizes = value.switch()?,
+            // Settings as --save-config writes them, one flag per line.
+            "--config" => {
+                let path: PathBuf = value.parse()?;
+                let text =
+                    fs::read_to_string(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+                // Only the line ending goes: spaces may be part of a value.
+                let lines = text
+                    .split('\n')
+                    .map(|line| line.strip_suffix('\r').unwrap_or(line));
+                for line in lines.filter(|line| !line.trim().is_empty()) {
+                    unescape(line)
+                        .and_then(|line| self.set(&line, &mut std::iter::empty()))
+                        .map_err(|e| format!("{}: {e}", path.display()))?;
+                }
+            }
+            _ => return Err(format!("unknown argument {arg}")),
+        }
+        Ok(())
+    }
+}
+
+// The flags a --watch-file can set.
+const DRAW_PARAMS: [&str; 4] = [
+    "draw-pattern",
+    "fill-color",
+    "frames-per-row",
+    "frame-number",
+];
+
+// For --save-config: backslashes, newlines and carriage returns as \\, \n
+// and \r, so the value fits on one line.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+// For --config: undoes escape().
+fn unescape(line: &str) -> Result<String, String> {
+    let mut unescaped = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        unescaped.push(match chars.next() {
+            Some('\\') => '\\',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            _ => return Err(format!("bad escape in {line:?}: expected \\\\, \\n or \\r")),
+        });
+    }
+    Ok(unescaped)
+}
+
+fn shown<T: Display>(value: &Option<T>) -> Option<String> {
+    value.as_ref().map(T::to_string)
+}
+
+// The value of a flag being parsed.
+struct Value<'a, I> {
+    flag: &'a str,
+    inline: Option<String>,
+    args: &'a mut I,
+}
+
+impl<I: Iterator<Item = String>> Value<'_, I> {
+    fn parse<T>(self) -> Result<T, String>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let flag = self.flag;
+        let value = match self.inline {
+            Some(value) => value,
+            None => self
+                .args
+                .next()
+                .ok_or_else(|| format!("{flag} needs a value"))?,
+        };
+        value
+            .parse()
+            .map_err(|e| format!("bad {flag} {value}: {e}"))
+    }
+
+    // Switches take no value on the command line, but can be turned off
+    // explicitly with e.g. --sync-commits=0.
+    fn switch(self) -> Result<bool, String> {
+        match self.inline.as_deref() {
+            None | Some("1" | "true") => Ok(true),
+            Some("" | "0" | "false") => Ok(false),
+            Some(v) => Err(format!("bad {} {v}: expected 0 or 1", self.flag)),
+        }
+    }
+}
+
+// What the unfilled part of the popup looks like.
+// Solid is the original demo; the others make misaligned or duplicated
+// buffers easier to spot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DrawPattern {
+    #[default]
+    Solid,
+    Checker,
+    Gradient,
+}
+
+impl FromStr for DrawPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "solid" => Ok(DrawPattern::Solid),
+            "checker" => Ok(DrawPattern::Checker),
+            "gradient" => Ok(DrawPattern::Gradient),
+            _ => Err("expected solid|checker|gradient".into()),
+        }
+    }
+}
+
+impl fmt::Display for DrawPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DrawPattern::Solid => "solid",
+            DrawPattern::Checker => "checker",
+            DrawPattern::Gradient => "gradient",
+        })
+    }
+}
+
+// When to send input_method.commit after changing the text.
+// The protocol intends one commit per done, but nothing stops a client
+// committing after every change.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitMode {
+    #[default]
+    PerKey,
+    Batched,
+}
+
+impl FromStr for CommitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "per-key" => Ok(CommitMode::PerKey),
+            "batched" => Ok(CommitMode::Batched),
+            _ => Err("expected per-key|batched".into()),
+        }
+    }
+}
+
+impl fmt::Display for CommitMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CommitMode::PerKey => "per-key",
+            CommitMode::Batched => "batched",
+        })
+    }
+}
+
+// Buffer rotations for --transform, counter-clockwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transform {
+    #[default]
+    Normal,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl FromStr for Transform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "normal" => Ok(Transform::Normal),
+            "90" => Ok(Transform::Rotate90),
+            "180" => Ok(Transform::Rotate180),
+            "270" => Ok(Transform::Rotate270),
+            _ => Err("expected normal|90|180|270".into()),
+        }
+    }
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Transform::Normal => "normal",
+            Transform::Rotate90 => "90",
+            Transform::Rotate180 => "180",
+            Transform::Rotate270 => "270",
+        })
+    }
+}
+
+// How to hide the popup: destroy the popup object (and make a new one to
+// show it again), or keep it and just unmap the surface by attaching no
+// buffer. Comparing the two separates lifecycle bugs from mapping bugs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HideMode {
+    #[default]
+    Destroy,
+    Unmap,
+}
+
+impl FromStr for HideMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "destroy" => Ok(HideMode::Destroy),
+            "unmap" => Ok(HideMode::Unmap),
+            _ => Err("expected destroy|unmap".into()),
+        }
+    }
+}
+
+impl fmt::Display for HideMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HideMode::Destroy => "destroy",
+            HideMode::Unmap => "unmap",
+        })
+    }
+}
+
+// Which key events to pass on to the app. Normally both, as a keyboard
+// would; apps seeing only one half may behave differently.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForwardOn {
+    Press,
+    Release,
+    #[default]
+    Both,
+}
+
+impl ForwardOn {
+    pub fn forwards(self, pressed: bool) -> bool {
+        match self {
+            ForwardOn::Press => pressed,
+            ForwardOn::Release => !pressed,
+            ForwardOn::Both => true,
+        }
+    }
+}
+
+impl FromStr for ForwardOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "press" => Ok(ForwardOn::Press),
+            "release" => Ok(ForwardOn::Release),
+            "both" => Ok(ForwardOn::Both),
+            _ => Err("expected press|release|both".into()),
+        }
+    }
+}
+
+impl fmt::Display for ForwardOn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ForwardOn::Press => "press",
+            ForwardOn::Release => "release",
+            ForwardOn::Both => "both",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(env: &[(&str, &str)], args: &[&str]) -> Result<Config, String> {
+        Config::resolve(
+            env.iter().map(|&(name, value)| (name.into(), value.into())),
+            args.iter().map(|&arg| arg.to_owned()),
+        )
+    }
+
+    #[test]
+    fn command_line_overrides_environment() {
+        let config = resolve(&[("IM_POPUP_EXIT_KEY", "2"), ("HOME", "/")], &[]).unwrap();
+        assert_eq!(config.exit_key.as_deref(), Some("2"));
+        let config = resolve(&[("IM_POPUP_EXIT_KEY", "2")], &["--exit-key=3"]).unwrap();
+        assert_eq!(config.exit_key.as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn environment_must_be_utf8_only_for_our_variables() {
+        use std::os::unix::ffi::OsStringExt;
+        let junk = || OsString::from_vec(vec![0xff]);
+        let env = [(OsString::from("JUNK"), junk())];
+        assert!(Config::resolve(env.into_iter(), std::iter::empty()).is_ok());
+        let env = [(OsString::from("IM_POPUP_EXIT_KEY"), junk())];
+        assert!(Config::resolve(env.into_iter(), std::iter::empty()).is_err());
+    }
+
+    #[test]
+    fn stride_must_fit_whole_rows_of_pixels() {
+        assert_eq!(resolve(&[], &["--stride=48"]).unwrap().stride, Some(48));
+        assert!(resolve(&[], &["--stride=40"]).is_ok());
+        assert!(resolve(&[], &["--stride=36"]).is_err());
+        assert!(resolve(&[], &["--stride=42"]).is_err());
+    }
+
+    #[test]
+    fn empty_first_commit_is_clear_on_open() {
+        assert!(
+            resolve(&[], &["--empty-first-commit"])
+                .unwrap()
+                .clear_on_open
+        );
+        assert!(
+            !resolve(&[], &["--empty-first-commit=false"])
+                .unwrap()
+                .clear_on_open
+        );
+    }
+
+    #[test]
+    fn forward_on_picks_presses_releases_or_both() {
+        let forwarded = |mode: ForwardOn| [mode.forwards(true), mode.forwards(false)];
+        assert_eq!(forwarded(ForwardOn::Press), [true, false]);
+        assert_eq!(forwarded(ForwardOn::Release), [false, true]);
+        assert_eq!(forwarded(ForwardOn::Both), [true, true]);
+        let config = resolve(&[], &["--forward-on=release"]).unwrap();
+        assert_eq!(config.forward_on, ForwardOn::Release);
+        assert!(resolve(&[], &["--forward-on=neither"]).is_err());
+    }
+
+    #[test]
+    fn override_keymap_needs_a_keymap() {
+        assert!(resolve(&[], &["--override-keymap"]).is_err());
+        let config = resolve(&[], &["--override-keymap", "--keymap=us.xkb"]).unwrap();
+        assert!(config.override_keymap);
+        assert!(resolve(&[], &["--check-keymap"]).is_err());
+    }
+
+    #[test]
+    fn watch_file_updates_draw_params() {
+        let mut config = Config::default();
+        config
+            .set_draw_params("# tweak\ndraw-pattern = checker\nfill-color = \"00ff00\"\n")
+            .unwrap();
+        assert_eq!(config.draw_pattern, DrawPattern::Checker);
+        assert_eq!(config.fill_color, Some("00ff00".parse().unwrap()));
+        assert_eq!(
+            config.set_draw_params("recommit = 2"),
+            Err("line 1: recommit can't be changed while running".into())
+        );
+        assert_eq!(config.recommit, 0);
+    }
+
+    #[test]
+    fn deterministic_time_rules_out_real_time_flags() {
+        assert!(
+            resolve(&[], &["--deterministic-time"])
+                .unwrap()
+                .deterministic_time
+        );
+        assert!(resolve(&[], &["--deterministic-time", "--heartbeat=100"]).is_err());
+        assert!(resolve(&[], &["--deterministic-time", "--trace-timing"]).is_err());
+    }
+
+    #[test]
+    fn saved_config_loads_back() {
+        let config = resolve(
+            &[],
+            &[
+                "--draw-pattern=gradient",
+                "--fill-color=ff8000",
+                "--auto-type=a\nb\n",
+                "--commit-string-on-toggle= x\\y ",
+                "--scancode-map=compose=127",
+                "--min-protocol=seat=5",
+                "--toggle-delay=150",
+            ],
+        )
+        .unwrap();
+        let saved = config.to_file();
+        assert!(saved.contains("--auto-type=a\\nb\\n\n"), "{saved}");
+        let path =
+            std::env::temp_dir().join(format!("sway-im-popup-config-{}", std::process::id()));
+        fs::write(&path, &saved).unwrap();
+        let loaded = resolve(&[], &[&format!("--config={}", path.display())]);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.to_file(), saved);
+        assert_eq!(loaded.auto_type.as_deref(), Some("a\nb\n"));
+        assert_eq!(loaded.commit_string_on_toggle.as_deref(), Some(" x\\y "));
+        assert_eq!(format!("{loaded:?}"), format!("{config:?}"));
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        for value in ["", "plain", "a\\nb", "\r\n\\"] {
+            assert_eq!(unescape(&escape(value)).as_deref(), Ok(value));
+        }
+        assert!(unescape("a\\tb").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+// If we panic, e.g. because attach fails after sway crashed under us, first
+// dump what we were doing, so the failure has context.
+
+use std::{collections::VecDeque, panic, sync::Mutex};
+
+// How many of the latest events to keep for the dump.
+const RECENT_EVENTS: usize = 20;
+
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    status: String::new(),
+    events: VecDeque::new(),
+});
+
+struct Context {
+    // As of the last dispatch.
+    status: String,
+    events: VecDeque<String>,
+}
+
+impl Context {
+    fn summary(&self) -> String {
+        let mut summary = format!("State before panic: {}\nRecent events:", self.status);
+        for event in &self.events {
+            summary += &format!("\n  {event}");
+        }
+        summary
+    }
+
+    fn record(&mut self, event: String) {
+        if self.events.len() == RECENT_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+pub fn install_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        // try_lock, in case we panicked while holding it.
+        if let Ok(context) = CONTEXT.try_lock() {
+            eprintln!("{}", context.summary());
+        }
+        default_hook(info);
+    }));
+}
+
+// Logs an event, and keeps it for the dump.
+pub fn log_event(event: String) {
+    println!("{event}");
+    if let Ok(mut context) = CONTEXT.lock() {
+        context.record(event);
+    }
+}
+
+pub fn set_status(status: String) {
+    if let Ok(mut context) = CONTEXT.lock() {
+        context.status = status;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_has_status_and_latest_events() {
+        let mut context = Context {
+            status: "popup shown".into(),
+            events: VecDeque::new(),
+        };
+        for i in 0..RECENT_EVENTS + 2 {
+            context.record(format!("event {i}"));
+        }
+        let summary = context.summary();
+        let mut lines = summary.lines();
+        assert_eq!(lines.next(), Some("State before panic: popup shown"));
+        assert_eq!(lines.next(), Some("Recent events:"));
+        assert_eq!(lines.next(), Some("  event 2"));
+        assert_eq!(
+            lines.last(),
+            Some(&*format!("  event {}", RECENT_EVENTS + 1))
+        );
+    }
+}
@@ -0,0 +1,92 @@
+// `sway-im-popup diff good.log bad.log` compares the output of two runs,
+// e.g. against a fixed and a buggy compositor, keystroke by keystroke.
+
+use std::{error::Error, fs, process};
+
+pub fn main(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let [a, b] = args else {
+        return Err("usage: diff A.log B.log".into());
+    };
+    let (a, b) = (fs::read_to_string(a)?, fs::read_to_string(b)?);
+    let divergences = diff(&keystrokes(&a), &keystrokes(&b));
+    for divergence in &divergences {
+        println!("{divergence}");
+    }
+    if !divergences.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Splits a log into the lines before each key press.
+// Numbers (times, serials, ids) are expected to differ and are masked out.
+fn keystrokes(log: &str) -> Vec<Vec<String>> {
+    let mut result = vec![Vec::new()];
+    for line in log.lines() {
+        if line.starts_with("Key {") && line.contains("Pressed") {
+            result.push(Vec::new());
+        }
+        result.last_mut().unwrap().push(mask_numbers(line));
+    }
+    result
+}
+
+fn mask_numbers(line: &str) -> String {
+    let mut result = String::new();
+    for c in line.chars() {
+        if !c.is_ascii_digit() {
+            result.push(c);
+        } else if !result.ends_with('#') {
+            result.push('#');
+        }
+    }
+    result
+}
+
+// Describes each keystroke where the two logs disagree.
+fn diff(a: &[Vec<String>], b: &[Vec<String>]) -> Vec<String> {
+    let mut result = Vec::new();
+    for key in 0..a.len().max(b.len()) {
+        let (lines_a, lines_b) = match (a.get(key), b.get(key)) {
+            (Some(lines_a), Some(lines_b)) => (lines_a, lines_b),
+            (Some(_), None) => {
+                result.push(format!("key {key}: only in A"));
+                continue;
+            }
+            (None, _) => {
+                result.push(format!("key {key}: only in B"));
+                continue;
+            }
+        };
+        if lines_a == lines_b {
+            continue;
+        }
+        let first = (0..).find(|&i| lines_a.get(i) != lines_b.get(i)).unwrap();
+        let or_end = |line: Option<&String>| line.cloned().unwrap_or("<end>".into());
+        result.push(format!(
+            "key {key}: {} vs {} lines, first difference at line {first}:\n  A: {}\n  B: {}",
+            lines_a.len(),
+            lines_b.len(),
+            or_end(lines_a.get(first)),
+            or_end(lines_b.get(first)),
+        ));
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_an_extra_event() {
+        let a = "Key { time: 10, key: 30, state: Pressed }\nDone\n";
+        let b = "Key { time: 25, key: 30, state: Pressed }\nDone\nDone\n";
+        assert_eq!(diff(&keystrokes(a), &keystrokes(a)), Vec::<String>::new());
+        // Numbers are masked, so only the extra line differs.
+        assert_eq!(
+            diff(&keystrokes(a), &keystrokes(b)),
+            ["key 1: 2 vs 3 lines, first difference at line 2:\n  A: <end>\n  B: Done"]
+        );
+    }
+}
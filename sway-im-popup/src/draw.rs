@@ -0,0 +1,466 @@
+// Filling popup buffers with recognizable content.
+
+use std::{fmt, ops::Range, str::FromStr};
+
+use crate::config::{Config, DrawPattern};
+
+const RED: [u8; 4] = [0u8, 0, 255, 255];
+const BLUE: [u8; 4] = [255u8, 0, 0, 255];
+const BLACK: [u8; 4] = [0u8, 0, 0, 255];
+const WHITE: [u8; 4] = [255u8, 255, 255, 255];
+
+// Dimensions of a buffer: width and height in pixels, stride in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+}
+
+impl Layout {
+    // Rows are padded to `stride` if it's given, which must be at least
+    // width * 4 (see Config::resolve).
+    pub fn new(width: usize, height: usize, stride: Option<usize>) -> Self {
+        Layout {
+            width,
+            height,
+            stride: stride.unwrap_or(width * 4),
+        }
+    }
+}
+
+// The default fill color.
+pub const FILL: [u8; 4] = RED;
+
+// Fill colors for --color-cycle, one per popup.
+pub const PALETTE: [[u8; 4]; 6] = [
+    RED,
+    [0, 255, 0, 255],
+    [0, 255, 255, 255],
+    [255, 0, 255, 255],
+    [255, 255, 0, 255],
+    WHITE,
+];
+
+// Reads a --palette file: one color per line, blank lines ignored.
+pub fn parse_palette(text: &str) -> Result<Vec<[u8; 4]>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let color: Color = line
+                .trim()
+                .parse()
+                .map_err(|e| format!("line {}: {e}", i + 1))?;
+            Ok(color.0)
+        })
+        .collect()
+}
+
+// The unfilled color of the popup.
+pub const BACKGROUND: [u8; 4] = BLUE;
+
+// To --initial-color, or the background.
+pub fn clear(data: &mut [u8], config: &Config) {
+    fill(data, config.initial_color.map_or(BACKGROUND, |c| c.0));
+}
+
+pub fn fill(data: &mut [u8], color: [u8; 4]) {
+    for pix in data.chunks_exact_mut(4) {
+        pix.copy_from_slice(&color);
+    }
+}
+
+// An opaque color given as RRGGBB (or #RRGGBB), stored in buffer byte order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Color(pub [u8; 4]);
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("expected a color like ff0000, got {s:?}"));
+        }
+        let rgb = u32::from_str_radix(hex, 16).unwrap();
+        Ok(Color((0xff000000 | rgb).to_le_bytes()))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [b, g, r, _] = self.0;
+        write!(f, "{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+// Draws the given animation frame: initially blue, filling up with `fill`
+// (normally red) at 1px per --frames-per-row frames. With --static-fill it's
+// all `fill` from the start.
+pub fn draw_into(data: &mut [u8], layout: Layout, config: &Config, frame: usize, fill: [u8; 4]) {
+    draw_rows(data, layout, config, frame, fill, 0..layout.height);
+}
+
+// Like draw_into, but only touches `rows`: the others are assumed to hold
+// the same frame already.
+pub fn draw_rows(
+    data: &mut [u8],
+    layout: Layout,
+    config: &Config,
+    frame: usize,
+    fill: [u8; 4],
+    rows: Range<usize>,
+) {
+    let height = layout.height;
+    if config.static_fill {
+        // No animation: the fill color everywhere, whatever the frame.
+        for row in rows_mut(data, layout).take(rows.end).skip(rows.start) {
+            for pix in row.chunks_exact_mut(4) {
+                pix.copy_from_slice(&fill);
+            }
+        }
+        return;
+    }
+    for (y, row) in rows_mut(data, layout).enumerate() {
+        if !rows.contains(&y) {
+            continue;
+        }
+        for (x, pix) in row.chunks_exact_mut(4).enumerate() {
+            if y < frame / config.frames_per_row {
+                pix.copy_from_slice(&fill);
+                continue;
+            }
+            match config.draw_pattern {
+                DrawPattern::Solid => pix.copy_from_slice(&BACKGROUND),
+                DrawPattern::Checker => {
+                    pix.copy_from_slice(if (x + y) % 2 == 0 { &BLUE } else { &BLACK })
+                }
+                DrawPattern::Gradient => {
+                    pix.copy_from_slice(&BLUE);
+                    pix[0] = (255 * (height - y) / height) as u8;
+                }
+            }
+        }
+    }
+    if config.frame_number {
+        draw_number(data, layout, frame);
+    }
+}
+
+// For --mark-first-frame: diagonal white stripes on the background, drawn
+// in place of a popup's first animation frame.
+pub fn stripes(data: &mut [u8], layout: Layout) {
+    for (y, row) in rows_mut(data, layout).enumerate() {
+        for (x, pix) in row.chunks_exact_mut(4).enumerate() {
+            let stripe = (x + y) % layout.width == 0;
+            pix.copy_from_slice(if stripe { &WHITE } else { &BACKGROUND });
+        }
+    }
+}
+
+// The rows that differ between two frames, as one range (maybe empty).
+pub fn changed_rows(layout: Layout, config: &Config, old: usize, new: usize) -> Range<usize> {
+    if config.static_fill {
+        return 0..0;
+    }
+    let (old_fill, new_fill) = (old / config.frames_per_row, new / config.frames_per_row);
+    let mut start = old_fill.min(new_fill);
+    let mut end = old_fill.max(new_fill);
+    if config.frame_number && old != new {
+        // The number's digits go from row 1 downwards, 6 rows each.
+        let digits = old.max(new).to_string().len();
+        start = 0;
+        end = end.max(1 + digits * 6);
+    }
+    start.min(layout.height)..end.min(layout.height)
+}
+
+// The pixels of each row, leaving out any padding.
+fn rows_mut(data: &mut [u8], layout: Layout) -> impl Iterator<Item = &mut [u8]> {
+    data.chunks_exact_mut(layout.stride)
+        .take(layout.height)
+        .map(move |row| &mut row[..layout.width * 4])
+}
+
+fn rows(data: &[u8], layout: Layout) -> impl Iterator<Item = &[u8]> {
+    data.chunks_exact(layout.stride)
+        .take(layout.height)
+        .map(move |row| &row[..layout.width * 4])
+}
+
+// The buffer's pixels without padding, i.e. as if stride were width * 4.
+pub fn unpadded(data: &[u8], layout: Layout) -> Vec<u8> {
+    rows(data, layout).collect::<Vec<_>>().concat()
+}
+
+// 3x5 bitmap digits, one row per byte, high bit on the left.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+// Writes `n` in the top-left corner. The popup is too narrow for more than a
+// couple of digits side by side, so they're stacked top to bottom.
+fn draw_number(data: &mut [u8], layout: Layout, n: usize) {
+    for (i, digit) in n.to_string().bytes().enumerate() {
+        let glyph = &DIGITS[(digit - b'0') as usize];
+        for (row, bits) in glyph.iter().enumerate() {
+            let y = 1 + i * 6 + row;
+            if y >= layout.height {
+                return;
+            }
+            for col in (0..3).take_while(|col| 1 + col < layout.width) {
+                if bits & (0b100 >> col) != 0 {
+                    let offset = y * layout.stride + (1 + col) * 4;
+                    data[offset..offset + 4].copy_from_slice(&WHITE);
+                }
+            }
+        }
+    }
+}
+
+// Renders a buffer as a JSON array of rows, each an array of [r, g, b, a].
+pub fn to_json(data: &[u8], layout: Layout) -> String {
+    let rows: Vec<String> = rows(data, layout)
+        .map(|row| {
+            let pixels: Vec<String> = row
+                .chunks_exact(4)
+                // Argb8888 is stored little-endian, i.e. as BGRA.
+                .map(|p| format!("[{},{},{},{}]", p[2], p[1], p[0], p[3]))
+                .collect();
+            format!("[{}]", pixels.join(","))
+        })
+        .collect();
+    format!("[\n{}\n]\n", rows.join(",\n"))
+}
+
+// Parses the output of `to_json` back into a buffer.
+pub fn from_json(json: &str) -> Result<(Layout, Vec<u8>), String> {
+    let mut rows: Vec<Vec<u8>> = Vec::new();
+    let (mut depth, mut number, mut pixel) = (0, String::new(), Vec::new());
+    for c in json.chars() {
+        match c {
+            '[' => {
+                depth += 1;
+                if depth == 2 {
+                    rows.push(Vec::new());
+                }
+            }
+            ']' | ',' => {
+                if !number.is_empty() {
+                    pixel.push(number.parse::<u8>().map_err(|e| format!("{number}: {e}"))?);
+                    number.clear();
+                }
+                if c == ']' {
+                    if depth == 3 {
+                        let [r, g, b, a] = pixel[..] else {
+                            return Err(format!("expected 4 channels, got {pixel:?}"));
+                        };
+                        rows.last_mut().unwrap().extend([b, g, r, a]);
+                        pixel.clear();
+                    }
+                    depth -= 1;
+                }
+            }
+            '0'..='9' if depth == 3 => number.push(c),
+            c if c.is_whitespace() => {}
+            _ => return Err(format!("unexpected {c:?}")),
+        }
+    }
+    let width = rows.first().map_or(0, |row| row.len() / 4);
+    if rows.iter().any(|row| row.len() != width * 4) {
+        return Err("rows have different lengths".into());
+    }
+    Ok((Layout::new(width, rows.len(), None), rows.concat()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The default size of the popup.
+    const LAYOUT: Layout = Layout {
+        width: 10,
+        height: 300,
+        stride: 40,
+    };
+
+    fn pixel(data: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let offset = y * LAYOUT.stride + x * 4;
+        data[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn checker_alternates() {
+        let config = Config {
+            draw_pattern: DrawPattern::Checker,
+            ..Default::default()
+        };
+        let mut data = vec![0; LAYOUT.stride * LAYOUT.height];
+        draw_into(&mut data, LAYOUT, &config, 0, FILL);
+        assert_eq!(pixel(&data, 0, 0), BLUE);
+        assert_eq!(pixel(&data, 1, 0), BLACK);
+        assert_eq!(pixel(&data, 0, 1), BLACK);
+        assert_eq!(pixel(&data, 1, 1), BLUE);
+    }
+
+    #[test]
+    fn frame_number_glyphs() {
+        let config = Config {
+            frame_number: true,
+            ..Default::default()
+        };
+        let mut data = vec![0; LAYOUT.stride * LAYOUT.height];
+        draw_into(&mut data, LAYOUT, &config, 42, FILL);
+        // "4" from row 1, then "2" from row 7, each at x = 1..4, over the
+        // red of 42 frames (4 rows) and then blue.
+        let expected = [
+            "     ", " # # ", " # # ", " ### ", "   # ", "   # ", //
+            "     ", " ### ", "   # ", " ### ", " #   ", " ### ",
+        ];
+        for (y, row) in expected.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                let color = match c {
+                    '#' => WHITE,
+                    _ if y < 4 => RED,
+                    _ => BLUE,
+                };
+                assert_eq!(pixel(&data, x, y), color, "at {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn clearing_fills_with_background() {
+        let mut data = vec![0; LAYOUT.stride * LAYOUT.height];
+        // Mostly red, as a long-lived popup leaves it.
+        draw_into(
+            &mut data,
+            LAYOUT,
+            &Config::default(),
+            10 * LAYOUT.height / 2,
+            FILL,
+        );
+        clear(&mut data, &Config::default());
+        assert!(data.chunks_exact(4).all(|pix| pix == BACKGROUND));
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let layout = Layout::new(2, 2, None);
+        let data = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        let json = "[\n[[3,2,1,4],[7,6,5,8]],\n[[11,10,9,12],[15,14,13,16]]\n]\n";
+        assert_eq!(to_json(&data, layout), json);
+        assert_eq!(from_json(json).unwrap(), (layout, data.to_vec()));
+        assert!(from_json("[[[1,2,3]]]").is_err());
+    }
+
+    #[test]
+    fn padded_stride_skips_padding() {
+        let config = Config {
+            draw_pattern: DrawPattern::Checker,
+            ..Default::default()
+        };
+        let layout = Layout::new(2, 2, Some(12));
+        let mut data = vec![7; layout.stride * layout.height];
+        draw_into(&mut data, layout, &config, 0, FILL);
+        assert_eq!(data[0..8], [BLUE, BLACK].concat());
+        assert_eq!(data[12..20], [BLACK, BLUE].concat());
+        // Padding is left alone.
+        assert_eq!(data[8..12], [7; 4]);
+        assert_eq!(data[20..24], [7; 4]);
+        assert_eq!(unpadded(&data, layout), [BLUE, BLACK, BLACK, BLUE].concat());
+    }
+
+    #[test]
+    fn colors_are_rrggbb_in_buffer_order() {
+        let color: Color = "#ff8000".parse().unwrap();
+        assert_eq!(color.0, [0x00, 0x80, 0xff, 0xff]);
+        assert_eq!(color.to_string(), "ff8000");
+        assert!("ff80".parse::<Color>().is_err());
+        assert!("gg8000".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn palette_file() {
+        assert_eq!(parse_palette("ff0000\n\n#0000ff\n"), Ok(vec![RED, BLUE]));
+        assert_eq!(
+            parse_palette("ff0000\nblue\n"),
+            Err("line 2: expected a color like ff0000, got \"blue\"".into())
+        );
+        assert_eq!(parse_palette(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn changed_rows_between_frames() {
+        let layout = Layout::new(4, 100, None);
+        let mut config = Config::default();
+        // 10 frames per row.
+        assert_eq!(changed_rows(layout, &config, 12, 13), 1..1);
+        assert_eq!(changed_rows(layout, &config, 19, 31), 1..3);
+        assert_eq!(changed_rows(layout, &config, 995, 1005), 99..100);
+        config.frame_number = true;
+        // Two digits, 6 rows each, from row 1.
+        assert_eq!(changed_rows(layout, &config, 12, 13), 0..13);
+    }
+
+    #[test]
+    fn redrawing_changed_rows_matches_a_full_draw() {
+        let layout = Layout::new(4, 10, None);
+        let config = Config {
+            frame_number: true,
+            ..Default::default()
+        };
+        let (old, new) = (17, 34);
+        let mut partial = vec![0; layout.stride * layout.height];
+        draw_into(&mut partial, layout, &config, old, FILL);
+        let rows = changed_rows(layout, &config, old, new);
+        draw_rows(&mut partial, layout, &config, new, FILL, rows);
+        let mut full = vec![0; layout.stride * layout.height];
+        draw_into(&mut full, layout, &config, new, FILL);
+        assert_eq!(partial, full);
+    }
+
+    #[test]
+    fn first_frame_stripes() {
+        let layout = Layout::new(3, 4, None);
+        let mut data = vec![0; layout.stride * layout.height];
+        stripes(&mut data, layout);
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                let expected = if (x + y) % 3 == 0 { WHITE } else { BACKGROUND };
+                let offset = y * layout.stride + x * 4;
+                assert_eq!(data[offset..offset + 4], expected, "at {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn static_fill_never_changes() {
+        let config = Config {
+            static_fill: true,
+            frame_number: true,
+            ..Default::default()
+        };
+        let draw = |frame| {
+            let mut data = vec![0; LAYOUT.stride * LAYOUT.height];
+            draw_into(&mut data, LAYOUT, &config, frame, FILL);
+            data
+        };
+        let first = draw(0);
+        assert!(first.chunks_exact(4).all(|pix| pix == FILL));
+        for frame in [1, 10, 299] {
+            assert_eq!(draw(frame), first, "frame {frame}");
+        }
+        assert!(changed_rows(LAYOUT, &config, 0, 299).is_empty());
+    }
+}
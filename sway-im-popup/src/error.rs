@@ -0,0 +1,109 @@
+// Failures worth telling apart in scripts, each with its own exit code.
+// Anything else exits with 1.
+
+use std::{error::Error, fmt, process::ExitCode};
+
+use wayland_client::{globals::BindError, ConnectError};
+
+#[derive(Debug)]
+pub enum AppError {
+    Config(String),
+    ConnectFailed(ConnectError),
+    MissingGlobal(&'static str),
+    BindFailed(&'static str, BindError),
+    BufferError(String),
+    ProtocolTooOld(String),
+    ScenarioFailed(String),
+    // Anything else, e.g. the compositor going away for good.
+    Other(Box<dyn Error>),
+}
+
+impl AppError {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            AppError::Config(_) => 2,
+            AppError::ConnectFailed(_) => 3,
+            AppError::MissingGlobal(_) => 4,
+            AppError::BindFailed(..) => 5,
+            AppError::BufferError(_) => 6,
+            AppError::ProtocolTooOld(_) => 7,
+            AppError::ScenarioFailed(_) => 8,
+            AppError::Other(_) => 1,
+        })
+    }
+
+    // Tells a global the compositor doesn't have from one it has at the
+    // wrong version.
+    pub fn bind(interface: &'static str, err: BindError) -> Self {
+        match err {
+            BindError::NotPresent => AppError::MissingGlobal(interface),
+            err => AppError::BindFailed(interface, err),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::Config(e) => write!(f, "{e}"),
+            AppError::ConnectFailed(e) => write!(f, "can't connect to the compositor: {e}"),
+            AppError::MissingGlobal(interface) => {
+                write!(f, "the compositor doesn't support {interface}")
+            }
+            AppError::BindFailed(interface, e) => write!(f, "can't bind {interface}: {e}"),
+            AppError::BufferError(e) => write!(f, "can't create buffers: {e}"),
+            AppError::ProtocolTooOld(e) => write!(f, "{e}"),
+            AppError::ScenarioFailed(e) => write!(f, "scenario failed at {e}"),
+            AppError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for AppError {}
+
+// Errors from deeper down travel boxed, so the reconnect loop can look inside
+// them. Any AppError among them keeps its variant.
+impl From<Box<dyn Error>> for AppError {
+    fn from(err: Box<dyn Error>) -> Self {
+        match err.downcast() {
+            Ok(err) => *err,
+            Err(err) => AppError::Other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn variants_are_distinct() {
+        let errors = [
+            AppError::Config("bad flag".into()),
+            AppError::ConnectFailed(ConnectError::NoCompositor),
+            AppError::bind("wl_seat", BindError::NotPresent),
+            AppError::bind("wl_seat", BindError::UnsupportedVersion),
+            AppError::BufferError("no memory".into()),
+            AppError::ProtocolTooOld("too old".into()),
+            AppError::ScenarioFailed("line 1".into()),
+            AppError::Other("compositor gone".into()),
+        ];
+        let codes: HashSet<String> = errors
+            .iter()
+            .map(|e| format!("{:?}", e.exit_code()))
+            .collect();
+        let messages: HashSet<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(codes.len(), errors.len());
+        assert_eq!(messages.len(), errors.len());
+        assert!(matches!(errors[2], AppError::MissingGlobal("wl_seat")));
+    }
+
+    #[test]
+    fn boxed_app_errors_keep_their_variant() {
+        let boxed: Box<dyn Error> = AppError::BufferError("no memory".into()).into();
+        assert!(matches!(AppError::from(boxed), AppError::BufferError(_)));
+        let boxed: Box<dyn Error> = "other".into();
+        assert!(matches!(AppError::from(boxed), AppError::Other(_)));
+    }
+}
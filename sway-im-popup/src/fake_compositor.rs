@@ -0,0 +1,234 @@
+// For tests: a compositor that advertises the globals a sway session has,
+// records every request we make, and only sends events when told to.
+
+use std::{
+    ffi::CString,
+    os::{
+        fd::{OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use smithay_client_toolkit::reexports::protocols::xdg::shell::client::xdg_wm_base::XdgWmBase;
+use wayland_backend::{
+    protocol::{Argument, Interface, Message},
+    server::{Backend, ClientId, GlobalHandler, GlobalId, Handle, ObjectData, ObjectId},
+};
+use wayland_client::{
+    protocol::{wl_compositor::WlCompositor, wl_output::WlOutput, wl_seat::WlSeat, wl_shm::WlShm},
+    Connection, Proxy,
+};
+use wayland_protocols_misc::{
+    zwp_input_method_v2::client::zwp_input_method_manager_v2::ZwpInputMethodManagerV2,
+    zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1,
+};
+
+pub struct FakeCompositor {
+    backend: Backend<State>,
+    state: State,
+    globals: Vec<GlobalId>,
+}
+
+#[derive(Default)]
+struct State {
+    // Every request so far, as e.g. "wl_surface@3.attach(wl_buffer@7, 0, 0)".
+    requests: Vec<String>,
+    // Every object the client has created, oldest first.
+    objects: Vec<ObjectId>,
+}
+
+impl FakeCompositor {
+    // With the globals we need, at the versions sway has.
+    pub fn new() -> (Self, Connection) {
+        Self::without("")
+    }
+
+    // As new(), but with no `missing` global.
+    pub fn without(missing: &str) -> (Self, Connection) {
+        let globals: [(&Interface, u32); 7] = [
+            (WlSeat::interface(), 9),
+            (ZwpVirtualKeyboardManagerV1::interface(), 1),
+            (ZwpInputMethodManagerV2::interface(), 1),
+            (WlCompositor::interface(), 6),
+            (WlShm::interface(), 1),
+            (WlOutput::interface(), 4),
+            (XdgWmBase::interface(), 5),
+        ];
+        let backend = Backend::new().unwrap();
+        let (server, client) = UnixStream::pair().unwrap();
+        backend
+            .handle()
+            .insert_client(server, Arc::new(()))
+            .unwrap();
+        let globals = globals
+            .into_iter()
+            .filter(|(interface, _)| interface.name != missing)
+            .map(|(interface, version)| {
+                let global = Arc::new(Global);
+                backend
+                    .handle()
+                    .create_global::<State>(interface, version, global)
+            })
+            .collect();
+        let fake = FakeCompositor {
+            backend,
+            state: State::default(),
+            globals,
+        };
+        (fake, Connection::from_socket(client).unwrap())
+    }
+
+    // Handles the requests sent so far, and sends back any events.
+    pub fn dispatch(&mut self) {
+        self.backend.dispatch_all_clients(&mut self.state).unwrap();
+        self.backend.flush(None).unwrap();
+    }
+
+    // Keeps dispatching while `f` waits on a roundtrip.
+    pub fn serve<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    self.dispatch();
+                    thread::sleep(Duration::from_millis(1));
+                }
+            });
+            let result = f();
+            done.store(true, Ordering::Relaxed);
+            result
+        })
+    }
+
+    // The requests since the last call.
+    pub fn take_requests(&mut self) -> Vec<String> {
+        self.dispatch();
+        std::mem::take(&mut self.state.requests)
+    }
+
+    // The newest live object the client made with `interface`.
+    pub fn newest(&self, interface: &str) -> ObjectId {
+        let handle = self.backend.handle();
+        let alive = |id: &&ObjectId| handle.object_info((*id).clone()).is_ok();
+        self.state
+            .objects
+            .iter()
+            .rev()
+            .filter(alive)
+            .find(|id| id.interface().name == interface)
+            .unwrap_or_else(|| panic!("no {interface}"))
+            .clone()
+    }
+
+    // Kills the client with a protocol error on `object`.
+    pub fn post_error(&mut self, object: &ObjectId, code: u32, message: &str) {
+        let message = CString::new(message).unwrap();
+        self.backend
+            .handle()
+            .post_error(object.clone(), code, message);
+        // The error is only sent, and the client dropped, as clients are
+        // next dispatched.
+        self.dispatch();
+    }
+
+    // As when e.g. an output is unplugged.
+    pub fn remove_global(&mut self, interface: &str) {
+        let handle = self.backend.handle();
+        let index = self
+            .globals
+            .iter()
+            .position(|id| {
+                let info = handle.global_info(id.clone()).unwrap();
+                info.interface.name == interface
+            })
+            .unwrap_or_else(|| panic!("no {interface} global"));
+        handle.remove_global::<State>(self.globals.remove(index));
+        self.backend.flush(None).unwrap();
+    }
+
+    // Sends `event` from `object`, with the given arguments.
+    pub fn send(&mut self, object: &ObjectId, event: &str, args: Vec<Argument<ObjectId, RawFd>>) {
+        let opcode = object
+            .interface()
+            .events
+            .iter()
+            .position(|desc| desc.name == event)
+            .unwrap_or_else(|| panic!("no {event} event"));
+        let msg = Message {
+            sender_id: object.clone(),
+            opcode: opcode as u16,
+            args: args.into(),
+        };
+        self.backend.handle().send_event(msg).unwrap();
+        self.backend.flush(None).unwrap();
+    }
+}
+
+struct Global;
+impl GlobalHandler<State> for Global {
+    fn bind(
+        self: Arc<Self>,
+        _: &Handle,
+        state: &mut State,
+        _: ClientId,
+        _: GlobalId,
+        object: ObjectId,
+    ) -> Arc<dyn ObjectData<State>> {
+        state.objects.push(object);
+        Arc::new(Recorder)
+    }
+}
+
+struct Recorder;
+impl ObjectData<State> for Recorder {
+    fn request(
+        self: Arc<Self>,
+        _: &Handle,
+        state: &mut State,
+        _: ClientId,
+        msg: Message<ObjectId, OwnedFd>,
+    ) -> Option<Arc<dyn ObjectData<State>>> {
+        let interface = msg.sender_id.interface();
+        let desc = &interface.requests[msg.opcode as usize];
+        let args: Vec<String> = msg.args.iter().map(describe).collect();
+        let sender = name(&msg.sender_id);
+        state
+            .requests
+            .push(format!("{sender}.{}({})", desc.name, args.join(", ")));
+        let created = msg.args.iter().find_map(|arg| match arg {
+            Argument::NewId(id) => Some(id.clone()),
+            _ => None,
+        });
+        created.map(|id| {
+            state.objects.push(id);
+            self as Arc<dyn ObjectData<State>>
+        })
+    }
+
+    fn destroyed(self: Arc<Self>, _: &Handle, _: &mut State, _: ClientId, _: ObjectId) {}
+}
+
+// As the client would print it, without the server's client number.
+fn name(id: &ObjectId) -> String {
+    format!("{}@{}", id.interface().name, id.protocol_id())
+}
+
+fn describe(arg: &Argument<ObjectId, OwnedFd>) -> String {
+    match arg {
+        Argument::Int(value) => value.to_string(),
+        Argument::Uint(value) => value.to_string(),
+        Argument::Fixed(value) => (f64::from(*value) / 256.0).to_string(),
+        Argument::Str(Some(value)) => format!("{value:?}"),
+        Argument::Str(None) => "nil".to_owned(),
+        Argument::Object(id) if id.is_null() => "nil".to_owned(),
+        Argument::Object(id) | Argument::NewId(id) => name(id),
+        Argument::Array(_) => "<array>".to_owned(),
+        Argument::Fd(_) => "<fd>".to_owned(),
+    }
+}
@@ -0,0 +1,95 @@
+// Where keyboard events go once we've seen them: normally to the app via
+// the virtual keyboard, or with --vk-to-log just to stdout, so scripted runs
+// don't need a focused app.
+
+use std::os::fd::BorrowedFd;
+#[cfg(test)]
+use std::{cell::RefCell, rc::Rc};
+
+use wayland_client::Proxy;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+
+pub trait KeySink {
+    fn keymap(&self, format: u32, fd: BorrowedFd, size: u32);
+    fn key(&self, time: u32, key: u32, state: u32);
+    fn modifiers(&self, depressed: u32, latched: u32, locked: u32, group: u32);
+    // The protocol version, if there's a real virtual keyboard behind this.
+    fn version(&self) -> Option<u32>;
+    // What --log-requests calls it.
+    fn object(&self) -> String;
+}
+
+impl KeySink for ZwpVirtualKeyboardV1 {
+    fn keymap(&self, format: u32, fd: BorrowedFd, size: u32) {
+        ZwpVirtualKeyboardV1::keymap(self, format, fd, size);
+    }
+
+    fn key(&self, time: u32, key: u32, state: u32) {
+        ZwpVirtualKeyboardV1::key(self, time, key, state);
+    }
+
+    fn modifiers(&self, depressed: u32, latched: u32, locked: u32, group: u32) {
+        ZwpVirtualKeyboardV1::modifiers(self, depressed, latched, locked, group);
+    }
+
+    fn version(&self) -> Option<u32> {
+        Some(Proxy::version(self))
+    }
+
+    fn object(&self) -> String {
+        self.id().to_string()
+    }
+}
+
+pub struct LogSink;
+
+impl KeySink for LogSink {
+    fn keymap(&self, format: u32, _: BorrowedFd, size: u32) {
+        println!("VK keymap: format {format}, {size} bytes");
+    }
+
+    fn key(&self, time: u32, key: u32, state: u32) {
+        println!("VK key: time {time}, key {key}, state {state}");
+    }
+
+    fn modifiers(&self, depressed: u32, latched: u32, locked: u32, group: u32) {
+        println!("VK modifiers: depressed {depressed}, latched {latched}, locked {locked}, group {group}");
+    }
+
+    fn version(&self) -> Option<u32> {
+        None
+    }
+
+    fn object(&self) -> String {
+        "log sink".into()
+    }
+}
+
+// Remembers what it was sent, for tests to check.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct RecordingSink(pub Rc<RefCell<Vec<String>>>);
+
+#[cfg(test)]
+impl KeySink for RecordingSink {
+    fn keymap(&self, format: u32, _: BorrowedFd, size: u32) {
+        self.0.borrow_mut().push(format!("keymap {format} {size}"));
+    }
+
+    fn key(&self, _: u32, key: u32, state: u32) {
+        self.0.borrow_mut().push(format!("key {key} {state}"));
+    }
+
+    fn modifiers(&self, depressed: u32, latched: u32, locked: u32, group: u32) {
+        let modifiers = format!("modifiers {depressed} {latched} {locked} {group}");
+        self.0.borrow_mut().push(modifiers);
+    }
+
+    fn version(&self) -> Option<u32> {
+        None
+    }
+
+    fn object(&self) -> String {
+        "recording sink".into()
+    }
+}
@@ -0,0 +1,76 @@
+// `--check-keymap` compares the keymap the grab sends with the one we give
+// the virtual keyboard (--keymap). If they differ, a key we pass on types
+// something other than what the user pressed.
+
+use xkbcommon::xkb;
+
+use crate::typing::compile;
+
+// How many differing keys to name before just counting them.
+const SHOWN: usize = 3;
+
+// Describes how the keys of `ours` differ from `grab`'s, comparing what each
+// types without modifiers. None if they type the same.
+pub fn differences(grab: &str, ours: &str) -> Result<Option<String>, String> {
+    let (grab, ours) = (compile(grab, "the grab's")?, compile(ours, "our")?);
+    let first = grab.min_keycode().raw().min(ours.min_keycode().raw());
+    let last = grab.max_keycode().raw().max(ours.max_keycode().raw());
+    let mut differing = Vec::new();
+    for code in first..=last {
+        let key = xkb::Keycode::new(code);
+        let (theirs, mine) = (
+            grab.key_get_syms_by_level(key, 0, 0),
+            ours.key_get_syms_by_level(key, 0, 0),
+        );
+        if theirs != mine {
+            // xkb keycodes are evdev ones plus 8.
+            differing.push(format!(
+                "key {} is {} rather than {}",
+                code.saturating_sub(8),
+                names(mine),
+                names(theirs)
+            ));
+        }
+    }
+    if differing.is_empty() {
+        return Ok(None);
+    }
+    let mut description = differing[..differing.len().min(SHOWN)].join(", ");
+    if differing.len() > SHOWN {
+        description += &format!(" (and {} more)", differing.len() - SHOWN);
+    }
+    Ok(Some(description))
+}
+
+fn names(syms: &[xkb::Keysym]) -> String {
+    match syms {
+        [] => "nothing".into(),
+        syms => syms
+            .iter()
+            .map(|&sym| xkb::keysym_get_name(sym))
+            .collect::<Vec<_>>()
+            .join("+"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::typing::keymap_text as keymap;
+
+    #[test]
+    fn same_layouts_match() {
+        assert_eq!(differences(&keymap("us"), &keymap("us")), Ok(None));
+    }
+
+    #[test]
+    fn different_layouts_are_described() {
+        let description = differences(&keymap("us"), &keymap("de")).unwrap().unwrap();
+        assert!(
+            description.starts_with("key 12 is ssharp rather than minus"),
+            "{description}"
+        );
+        assert!(description.contains(" more)"), "{description}");
+        assert!(differences("nonsense", &keymap("us")).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+// Names for evdev keycodes, so flags can say `esc` rather than `1`.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+// Parsed from `--scancode-map a=30,space=57`, adding to or overriding the
+// built-in names.
+#[derive(Clone, Debug, Default)]
+pub struct ScancodeMap(HashMap<String, u32>);
+
+impl ScancodeMap {
+    // Accepts a name, or a raw keycode.
+    pub fn resolve(&self, name: &str) -> Result<u32, String> {
+        if let Ok(code) = name.parse() {
+            return Ok(code);
+        }
+        self.0
+            .get(name)
+            .copied()
+            .or_else(|| builtin(name))
+            .ok_or_else(|| format!("unknown key {name}"))
+    }
+
+    // The keys to press, in order, to type `text` on a US layout without
+    // modifiers, for --auto-type.
+    pub fn keystrokes(&self, text: &str) -> Result<Vec<u32>, String> {
+        text.chars()
+            .map(|c| match c {
+                ' ' => self.resolve("space"),
+                '\n' => self.resolve("enter"),
+                // Not a name, so it can't be taken for a raw keycode.
+                '0'..='9' => builtin(&c.to_string()).ok_or_else(|| format!("can't type {c:?}")),
+                _ => self
+                    .resolve(&c.to_string())
+                    .map_err(|_| format!("can't type {c:?}")),
+            })
+            .collect()
+    }
+}
+
+impl FromStr for ScancodeMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut map = HashMap::new();
+        for entry in s.split(',').filter(|entry| !entry.is_empty()) {
+            let (name, code) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=code, got {entry}"))?;
+            let code = code.parse().map_err(|_| format!("bad keycode {code}"))?;
+            map.insert(name.to_owned(), code);
+        }
+        Ok(ScancodeMap(map))
+    }
+}
+
+impl fmt::Display for ScancodeMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut entries: Vec<String> = self
+            .0
+            .iter()
+            .map(|(name, code)| format!("{name}={code}"))
+            .collect();
+        entries.sort();
+        write!(f, "{}", entries.join(","))
+    }
+}
+
+// The US layout's main block, from linux/input-event-codes.h.
+fn builtin(name: &str) -> Option<u32> {
+    const ROWS: [(u32, &str); 4] = [
+        (2, "1234567890"),
+        (16, "qwertyuiop"),
+        (30, "asdfghjkl"),
+        (44, "zxcvbnm"),
+    ];
+    if let [c] = name.as_bytes() {
+        for (first, row) in ROWS {
+            if let Some(i) = row.bytes().position(|r| r == *c) {
+                return Some(first + i as u32);
+            }
+        }
+    }
+    Some(match name {
+        "esc" => 1,
+        "backspace" => 14,
+        "tab" => 15,
+        "enter" => 28,
+        "space" => 57,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_adds_to_and_overrides_builtin_names() {
+        let map: ScancodeMap = "compose=127,a=31".parse().unwrap();
+        assert_eq!(map.resolve("compose"), Ok(127));
+        assert_eq!(map.resolve("a"), Ok(31));
+        assert_eq!(map.resolve("esc"), Ok(1));
+        assert_eq!(map.resolve("42"), Ok(42));
+        assert!(map.resolve("nope").is_err());
+        assert!("a".parse::<ScancodeMap>().is_err());
+        assert!("a=x".parse::<ScancodeMap>().is_err());
+    }
+
+    #[test]
+    fn keystrokes_for_text() {
+        let map = ScancodeMap::default();
+        assert_eq!(map.keystrokes("hi"), Ok(vec![35, 23]));
+        assert_eq!(map.keystrokes("a 1\n"), Ok(vec![30, 57, 2, 28]));
+        assert_eq!(map.keystrokes("H"), Err("can't type 'H'".into()));
+    }
+}
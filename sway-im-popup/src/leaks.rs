@@ -0,0 +1,91 @@
+// `--leak-check` audits our own object lifecycle: every popup, surface and
+// buffer we create should be destroyed by the time we exit.
+
+use std::collections::BTreeMap;
+
+use wayland_client::{backend::ObjectId, Connection};
+
+// Only tracks anything if enabled, so long runs don't accumulate ids.
+pub struct Objects {
+    enabled: bool,
+    ids: Vec<ObjectId>,
+}
+
+impl Objects {
+    pub fn new(enabled: bool) -> Self {
+        Objects {
+            enabled,
+            ids: Vec::new(),
+        }
+    }
+
+    pub fn created(&mut self, id: ObjectId) {
+        if self.enabled {
+            self.ids.push(id);
+        }
+    }
+
+    pub fn report(&self, conn: &Connection) {
+        if !self.enabled {
+            return;
+        }
+        let mut counts = Counts::default();
+        for id in &self.ids {
+            counts.add(id.interface().name, conn.object_info(id.clone()).is_ok());
+        }
+        for verdict in counts.verdicts() {
+            match verdict {
+                Ok(message) => println!("Leak check: {message}"),
+                Err(message) => warning!("{message}"),
+            }
+        }
+    }
+}
+
+// Per interface: how many we created, and how many are still alive.
+#[derive(Default)]
+struct Counts(BTreeMap<&'static str, (usize, usize)>);
+
+impl Counts {
+    fn add(&mut self, interface: &'static str, alive: bool) {
+        let (created, alive_count) = self.0.entry(interface).or_insert((0, 0));
+        *created += 1;
+        *alive_count += usize::from(alive);
+    }
+
+    // One per interface, an error if any are still alive.
+    fn verdicts(&self) -> Vec<Result<String, String>> {
+        self.0
+            .iter()
+            .map(|(interface, &(created, alive))| {
+                if alive > 0 {
+                    Err(format!("leaked {alive} of {created} {interface}"))
+                } else {
+                    Ok(format!("destroyed all {created} {interface}"))
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_what_is_still_alive() {
+        let mut counts = Counts::default();
+        for _ in 0..3 {
+            counts.add("wl_surface", false);
+        }
+        assert_eq!(
+            counts.verdicts(),
+            [Ok("destroyed all 3 wl_surface".to_owned())]
+        );
+        counts.add("wl_surface", true);
+        assert_eq!(
+            counts.verdicts(),
+            [Err("leaked 1 of 4 wl_surface".to_owned())]
+        );
+    }
+}
@@ -18,28 +18,105 @@
 //  - the more panels we have, the faster the animation: the surface is getting
 //    multiple redraw calls per frame
 //  - when we deactivate the text area, sway crashes (if we showed >=2 panels)
+//
+// Running with --workaround creates a fresh surface for each popup instead of
+// reusing one, and everything works.
+
+// Prints a warning, which tests can check for with `tests::warnings`.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::warn(format!($($arg)*))
+    };
+}
 
-use std::{error::Error, os::fd::AsFd, sync::atomic::AtomicUsize};
+mod batches;
+mod clock;
+mod compare;
+mod compat;
+mod config;
+mod crash;
+mod diff;
+mod draw;
+mod error;
+#[cfg(test)]
+mod fake_compositor;
+mod key_sink;
+mod keymap_check;
+mod keys;
+mod leaks;
+mod mirror;
+mod nested;
+mod pixel_format;
+mod report;
+mod scenario;
+mod surrounding;
+mod trace;
+mod typing;
+
+use std::{
+    collections::VecDeque,
+    env,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, ErrorKind, Write},
+    mem,
+    ops::RangeInclusive,
+    os::{fd::AsFd, unix::fs::FileExt},
+    path::Path,
+    process::ExitCode,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
 use protocol::{
     wl_buffer::WlBuffer,
     wl_callback::{self, WlCallback},
     wl_compositor::WlCompositor,
+    wl_output::{self, WlOutput},
     wl_shm::{self, WlShm},
-    wl_surface::WlSurface,
+    wl_surface::{self, WlSurface},
 };
 
+use batches::Batches;
+use clock::{Clock, SystemClock, VirtualClock};
+use config::{CommitMode, Config, HideMode, Transform};
+use draw::Layout;
+use error::AppError;
+use key_sink::{KeySink, LogSink};
+use leaks::Objects;
+use mirror::Mirror;
+use report::Report;
+use rustix::fs::MemfdFlags;
+use scenario::Step;
 use smithay_client_toolkit::{
     delegate_registry,
     globals::ProvidesBoundGlobal,
+    reexports::{
+        calloop::{
+            self,
+            timer::{TimeoutAction, Timer},
+            EventLoop, LoopHandle,
+        },
+        calloop_wayland_source::WaylandSource,
+    },
     registry::{ProvidesRegistryState, RegistryState},
-    registry_handlers,
     shm::slot::{Buffer, SlotPool},
 };
+use surrounding::SurroundingText;
+use trace::Span;
+use typing::Typing;
 use wayland_client::{
+    backend::{protocol::ProtocolError, WaylandError},
     delegate_noop,
-    globals::registry_queue_init,
-    protocol::{self, wl_keyboard, wl_seat::WlSeat},
+    globals::{registry_queue_init, BindError, GlobalError, GlobalList},
+    protocol::{
+        self, wl_keyboard,
+        wl_pointer::{self, WlPointer},
+        wl_seat::{self, WlSeat},
+    },
     Connection, Dispatch, Proxy, QueueHandle, WEnum,
 };
 use wayland_protocols_misc::{
@@ -47,7 +124,7 @@ use wayland_protocols_misc::{
         zwp_input_method_keyboard_grab_v2::{self, ZwpInputMethodKeyboardGrabV2},
         zwp_input_method_manager_v2::ZwpInputMethodManagerV2,
         zwp_input_method_v2::{self, ZwpInputMethodV2},
-        zwp_input_popup_surface_v2::ZwpInputPopupSurfaceV2,
+        zwp_input_popup_surface_v2::{self, ZwpInputPopupSurfaceV2},
     },
     zwp_virtual_keyboard_v1::client::{
         zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1,
@@ -55,54 +132,744 @@ use wayland_protocols_misc::{
     },
 };
 
+// Popup size, unless --fit-rectangle says otherwise.
 const WIDTH: usize = 10;
 const HEIGHT: usize = 300;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let conn = Connection::connect_to_env().unwrap();
-    let (globals, mut event_queue) = registry_queue_init::<App>(&conn).unwrap();
+// How long to wait between attempts to reach a restarted compositor, and how
+// many attempts to make before deciding it's not coming back.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_ATTEMPTS: usize = 120;
+// How long to wait on exit for the compositor to release buffers.
+const RELEASE_TIMEOUT: Duration = Duration::from_millis(500);
+// How many buffers --buffer-age-emulation cycles through.
+const BUFFER_RING: usize = 3;
+// How often --wait-for-slot asks for the input method again.
+const SLOT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+// --auto-type's pace: a key every TYPING_INTERVAL, each held for KEY_HOLD.
+const TYPING_INTERVAL: Duration = Duration::from_millis(150);
+const KEY_HOLD: Duration = Duration::from_millis(60);
+// --fill-drift logs every this many frames, assuming this refresh rate
+// (in mHz) if we don't know the output's.
+const FILL_DRIFT_INTERVAL: usize = 60;
+const DEFAULT_REFRESH: i32 = 60_000;
+// How often to check whether the --watch-file has changed.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            e.exit_code()
+        }
+    }
+}
+
+fn try_main() -> Result<(), AppError> {
+    crash::install_hook();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "diff") {
+        return Ok(diff::main(&args[1..])?);
+    }
+    let config = Config::resolve(env::vars_os(), args.into_iter()).map_err(AppError::Config)?;
+    if let Some(path) = &config.save_config {
+        fs::write(path, config.to_file()).map_err(|e| AppError::Other(e.into()))?;
+    }
+    if let Some(reference) = &config.compare {
+        return Ok(compare::main(&config, reference)?);
+    }
+    if config.pixel_format_test {
+        let conn = Connection::connect_to_env().map_err(AppError::ConnectFailed)?;
+        return Ok(pixel_format::main(&conn)?);
+    }
+    if let Some(path) = &config.log_wire {
+        log_wire(path)?;
+    }
+    // Kept alive until the end of main, which stops sway again.
+    let _nested = config.launch_nested.then(nested::launch).transpose()?;
+
+    let mut report = Report::default();
+    let result = run_reconnecting(&config, &mut report);
+    if let Some(path) = &config.report {
+        report.error = result.as_ref().err().map(|e| e.to_string());
+        fs::write(path, report.to_json(&config)).map_err(|e| AppError::Other(e.into()))?;
+    }
+    Ok(result?)
+}
+
+// If the compositor goes away (e.g. sway restarts during a soak test),
+// wait for it to come back and start over with fresh state.
+fn run_reconnecting(config: &Config, report: &mut Report) -> Result<(), Box<dyn Error>> {
+    let deadline = config
+        .max_runtime
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut link = Link::Connected { reconnects: 0 };
+    let mut conn = Connection::connect_to_env().map_err(AppError::ConnectFailed)?;
+    loop {
+        match run(&conn, config, deadline, report) {
+            Err(e) if is_disconnect(e.as_ref()) => {
+                println!("Disconnected: {e}");
+                report.disconnected = true;
+            }
+            result => return result,
+        }
+        link = link.disconnected();
+        while let Link::Disconnected { .. } = link {
+            thread::sleep(RECONNECT_DELAY);
+            if expired(deadline, Instant::now()) {
+                println!("Reached --max-runtime while disconnected");
+                return Ok(());
+            }
+            let attempt = Connection::connect_to_env();
+            link = link.attempted(attempt.is_ok());
+            conn = attempt.unwrap_or(conn);
+        }
+        match link {
+            Link::Connected { reconnects } => {
+                println!("Reconnected (reconnection #{reconnects})");
+                report.reconnects = reconnects;
+            }
+            _ => return Err(format!("compositor gone for {RECONNECT_ATTEMPTS} attempts").into()),
+        }
+    }
+}
+
+fn expired(deadline: Option<Instant>, now: Instant) -> bool {
+    deadline.is_some_and(|deadline| now >= deadline)
+}
+
+// Whether we have the compositor, as far as reconnecting goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Link {
+    Connected { reconnects: usize },
+    // With how many attempts to reconnect have failed so far.
+    Disconnected { reconnects: usize, failed: usize },
+    GaveUp,
+}
+
+impl Link {
+    fn disconnected(self) -> Link {
+        match self {
+            Link::Connected { reconnects } => Link::Disconnected {
+                reconnects,
+                failed: 0,
+            },
+            link => link,
+        }
+    }
+
+    // After an attempt to reconnect, successful or not.
+    fn attempted(self, connected: bool) -> Link {
+        match self {
+            Link::Disconnected { reconnects, .. } if connected => Link::Connected {
+                reconnects: reconnects + 1,
+            },
+            Link::Disconnected { reconnects, failed } if failed + 1 < RECONNECT_ATTEMPTS => {
+                Link::Disconnected {
+                    reconnects,
+                    failed: failed + 1,
+                }
+            }
+            Link::Disconnected { .. } => Link::GaveUp,
+            link => link,
+        }
+    }
+}
+
+// wayland-client logs every message to stderr under WAYLAND_DEBUG, so send
+// stderr to `path`. Our own warnings end up in the log too, which gives them
+// context.
+fn log_wire(path: &Path) -> Result<(), Box<dyn Error>> {
+    rustix::stdio::dup2_stderr(File::create(path)?)?;
+    env::set_var("WAYLAND_DEBUG", "client");
+    Ok(())
+}
+
+// Whether an error means we lost the compositor, rather than e.g. a protocol
+// error that would just happen again.
+fn is_disconnect(err: &(dyn Error + 'static)) -> bool {
+    if let Some(GlobalError::Backend(e)) = err.downcast_ref() {
+        return matches!(e, WaylandError::Io(_));
+    }
+    // The event loop reports protocol errors as EPROTO.
+    if let Some(calloop::Error::IoError(e)) = err.downcast_ref() {
+        return matches!(
+            e.kind(),
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::UnexpectedEof
+        );
+    }
+    false
+}
+
+fn warn(message: String) {
+    eprintln!("WARNING: {message}");
+    #[cfg(test)]
+    tests::WARNINGS.with_borrow_mut(|warnings| warnings.push(message));
+}
+
+fn run(
+    conn: &Connection,
+    config: &Config,
+    deadline: Option<Instant>,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let (mut app, mut event_loop) = setup(conn, config)?;
+    if config.compat {
+        compat::print(&app.versions);
+        return Ok(());
+    }
+    if let Some(deadline) = deadline {
+        event_loop
+            .handle()
+            .insert_source(Timer::from_deadline(deadline), |_, _, app| {
+                println!("Reached --max-runtime");
+                app.shutdown();
+                TimeoutAction::Drop
+            })
+            .map_err(|e| e.error)?;
+    }
+    let result = (|| {
+        dispatch_until_exit(&mut app, &mut event_loop)?;
+        release_buffers(&mut app, &mut event_loop, RELEASE_TIMEOUT)?;
+        app.emit(app.surface.id(), "destroy", format_args!(""));
+        app.surface.destroy();
+        Ok::<_, calloop::Error>(())
+    })();
+    report.versions = std::mem::take(&mut app.versions);
+    report.frames += app.frame;
+    report.commits += app.commits;
+    report.popups_opened += app.popups_opened;
+    let err = conn.protocol_error();
+    if let Some(err) = &err {
+        report.protocol_errors.push(err.to_string());
+    }
+    if config.probe_popup_limit.is_some() {
+        match &err {
+            Some(err) => println!("Probe: {err} after {} popups", app.popups_opened),
+            None => println!("Probe: {} popups without errors", app.popups_opened),
+        }
+    }
+    if !app.protocol_error_reported {
+        if let Some(message) = protocol_error_report(err.as_ref(), app.last_popup_action) {
+            eprintln!("{message}");
+        }
+    }
+    result?;
+    if let Some(failure) = app.scenario_failure.take() {
+        return Err(AppError::ScenarioFailed(failure).into());
+    }
+    if let (Some(path), Some((layout, data))) = (&config.dump_last_buffer, &app.last_buffer) {
+        fs::write(path, draw::to_json(data, *layout))?;
+    }
+    // Buffers are only destroyed when dropped.
+    let objects = std::mem::replace(&mut app.objects, Objects::new(false));
+    drop(app);
+    objects.report(conn);
+    conn.flush()?;
+    Ok(())
+}
+
+// Binds `I` at the newest version both we and the compositor support.
+fn bind<I>(
+    globals: &GlobalList,
+    qh: &QueueHandle<App>,
+    supported: RangeInclusive<u32>,
+) -> Result<I, AppError>
+where
+    I: Proxy + 'static,
+    App: Dispatch<I, ()>,
+{
+    let interface = I::interface().name;
+    let advertised = globals.contents().with_list(|list| {
+        list.iter()
+            .find(|global| global.interface == interface)
+            .map(|global| global.version)
+    });
+    let version = advertised
+        .ok_or(BindError::NotPresent)
+        .and_then(|advertised| {
+            pick_version(advertised, supported).ok_or(BindError::UnsupportedVersion)
+        })
+        .map_err(|e| AppError::bind(interface, e))?;
+    globals
+        .bind(qh, version..=version, ())
+        .map_err(|e| AppError::bind(interface, e))
+}
+
+// The highest version in `supported` that's at most `advertised`.
+fn pick_version(advertised: u32, supported: RangeInclusive<u32>) -> Option<u32> {
+    let version = advertised.min(*supported.end());
+    supported.contains(&version).then_some(version)
+}
+
+// Runs the event loop until the app asks to exit. If dispatching fails, e.g.
+// because the compositor tore down our objects, the app shuts down first.
+fn dispatch_until_exit(
+    app: &mut App,
+    event_loop: &mut EventLoop<App>,
+) -> Result<(), calloop::Error> {
+    while !app.exit {
+        // Idle callbacks only run after a dispatch, so --stress mustn't wait
+        // in one for an event that may never come.
+        let timeout = app.stressing.then_some(Duration::ZERO);
+        let mut result = event_loop.dispatch(timeout, app);
+        crash::set_status(app.status());
+        app.check_frame_limit();
+        // The Wayland source drops protocol errors it reads from the socket,
+        // rather than failing the dispatch, so look for them ourselves.
+        if result.is_ok() && app.conn.protocol_error().is_some() {
+            result = Err(io::Error::from(rustix::io::Errno::PROTO).into());
+        }
+        if let Err(e) = result {
+            app.shutdown();
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// Unmaps, and gives the compositor up to `timeout` to release our buffer
+// before the surface goes away.
+fn release_buffers(
+    app: &mut App,
+    event_loop: &mut EventLoop<App>,
+    timeout: Duration,
+) -> Result<(), calloop::Error> {
+    app.emit(app.surface.id(), "attach", format_args!("nil, 0, 0"));
+    app.surface.attach(None, 0, 0);
+    app.emit(app.surface.id(), "commit", format_args!(""));
+    app.surface.commit();
+    app.buffer_attached = false;
+    let deadline = Instant::now() + timeout;
+    while app.buffer.slot().has_active_buffers() {
+        let now = Instant::now();
+        if now >= deadline {
+            warning!("compositor still holds our buffer, exiting anyway");
+            break;
+        }
+        event_loop.dispatch(deadline - now, app)?;
+    }
+    Ok(())
+}
+
+// Binds what we need and creates our App, with an event loop to run it.
+fn setup(
+    conn: &Connection,
+    config: &Config,
+) -> Result<(App, EventLoop<'static, App>), Box<dyn Error>> {
+    let (globals, event_queue) = registry_queue_init::<App>(conn)?;
     let qh = event_queue.handle();
+    let advertised: Vec<(String, u32)> = globals.contents().with_list(|list| {
+        list.iter()
+            .map(|global| (global.interface.clone(), global.version))
+            .collect()
+    });
+    config
+        .min_protocol
+        .check(&advertised)
+        .map_err(AppError::ProtocolTooOld)?;
+
+    let seat: WlSeat = bind(&globals, &qh, 1..=9)?;
+    let virtual_keyboard: Option<Box<dyn KeySink>> = if config.no_vk || config.ime_only {
+        None
+    } else if config.vk_to_log {
+        Some(Box::new(LogSink))
+    } else {
+        match bind::<ZwpVirtualKeyboardManagerV1>(&globals, &qh, 1..=1) {
+            Ok(vk_mgr) => Some(Box::new(vk_mgr.create_virtual_keyboard(&seat, &qh, ()))),
+            // The popup bug doesn't need key forwarding, so carry on without.
+            Err(e) if !config.require_vk => {
+                warning!("no virtual keyboard ({e}), keys won't reach the app");
+                None
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    if let (Some(vk), Some(path)) = (&virtual_keyboard, &config.keymap) {
+        let (keymap, size) = load_keymap(path)?;
+        let format = wl_keyboard::KeymapFormat::XkbV1.into();
+        log_request(
+            config,
+            vk.object(),
+            "keymap",
+            format_args!("{format}, <fd>, {size}"),
+        );
+        vk.keymap(format, keymap.as_fd(), size);
+    }
+    let im_mgr: ZwpInputMethodManagerV2 = bind(&globals, &qh, 1..=1)?;
+    // We need damage_buffer, from v4.
+    let compositor: WlCompositor = bind(&globals, &qh, 4..=6)?;
+    let shm: WlShm = bind(&globals, &qh, 1..=1)?;
+    // All of them, so we can say which one the popup lands on.
+    let outputs = globals.contents().with_list(|list| {
+        list.iter()
+            .filter(|global| global.interface == WlOutput::interface().name)
+            .filter_map(|global| {
+                let version = pick_version(global.version, 1..=4)?;
+                let output: WlOutput = globals.registry().bind(global.name, version, &qh, ());
+                let info = Output {
+                    global: global.name,
+                    ..Default::default()
+                };
+                Some((output, info))
+            })
+            .collect()
+    });
 
-    let seat: WlSeat = globals.bind(&qh, 1..=1, ())?;
-    let vk_mgr: ZwpVirtualKeyboardManagerV1 = globals.bind(&qh, 1..=1, ())?;
-    let im_mgr: ZwpInputMethodManagerV2 = globals.bind(&qh, 1..=1, ())?;
-    let compositor: WlCompositor = globals.bind(&qh, 4..=4, ())?;
-    let shm: WlShm = globals.bind(&qh, 1..=1, ())?;
+    let mut versions = vec![
+        ("wl_seat", seat.version()),
+        ("zwp_input_method_manager_v2", im_mgr.version()),
+        ("wl_compositor", compositor.version()),
+        ("wl_shm", shm.version()),
+    ];
+    if let Some(version) = virtual_keyboard.as_ref().and_then(|vk| vk.version()) {
+        versions.push(("zwp_virtual_keyboard_v1", version));
+    }
 
+    let mirror = if config.mirror {
+        Some(Mirror::new(bind(&globals, &qh, 1..=6)?, &compositor, &qh))
+    } else {
+        None
+    };
+    let palette = match &config.palette {
+        Some(path) => draw::parse_palette(&fs::read_to_string(path)?)
+            .map_err(|e| AppError::Config(format!("{}: {e}", path.display())))?,
+        None => draw::PALETTE.to_vec(),
+    };
+    let scenario = match &config.scenario {
+        Some(path) => scenario::parse(&fs::read_to_string(path)?, &config.scancode_map)
+            .map_err(|e| AppError::Config(format!("{}: {e}", path.display())))?,
+        None => Vec::new(),
+    };
     let input_method = im_mgr.get_input_method(&seat, &qh, ());
-    let surface = compositor.create_surface(&qh, ());
-    let mut shm_pool = SlotPool::new((WIDTH * HEIGHT * 4) as usize, &Provider(shm))?;
-    let initial_buffer = create_buffer(&mut shm_pool).0;
+    log_request(
+        config,
+        im_mgr.id(),
+        "get_input_method",
+        format_args!("{}, {}", seat.id(), input_method.id()),
+    );
+    let surface = create_surface(&compositor, &qh, config);
+    let layout = Layout::new(WIDTH, HEIGHT, config.stride);
+    let mut shm_pool = SlotPool::new(layout.stride * layout.height, &Provider(shm))
+        .map_err(|e| AppError::BufferError(e.to_string()))?;
+    let (initial_buffer, data) = create_buffer(&mut shm_pool, layout);
+    // Rather than whatever the pool's memory held.
+    draw::clear(data, config);
 
+    let event_loop = EventLoop::<App>::try_new()?;
+    let clock: Rc<dyn Clock> = if config.deterministic_time {
+        Rc::new(VirtualClock::new(Instant::now()))
+    } else {
+        Rc::new(SystemClock)
+    };
     let mut app = App {
+        loop_handle: event_loop.handle(),
+        config: config.clone(),
+        exit_key: config.keycode(&config.exit_key)?,
+        toggle_key: config.keycode(&config.toggle_key)?,
+        cancel_key: config.keycode(&config.cancel_key)?,
+        conn: conn.clone(),
+        commits: 0,
+        synced_commits: 0,
+        frame: 0,
         registry_state: RegistryState::new(&globals),
         compositor,
         shm_pool,
         input_method,
-        pending_active: false,
+        im_mgr,
+        seat,
+        slot_retry_interval: SLOT_RETRY_INTERVAL,
+        pending: ImeState::default(),
+        current: ImeState::default(),
+        done_count: 0,
+        commit_serial: 0,
+        uncommitted: false,
+        text_model: None,
         open_popup: None,
+        popup_unmapped: false,
         grabbed_keyboard: None,
-        virtual_keyboard: vk_mgr.create_virtual_keyboard(&seat, &qh, ()),
+        virtual_keyboard,
         surface,
+        surface_has_role: false,
+        outputs,
+        surface_outputs: Vec::new(),
+        objects: Objects::new(config.leak_check),
+        buffer_attached: false,
         buffer: initial_buffer,
+        buffer_frame: None,
+        popup_drawn: false,
+        spare_buffers: Vec::new(),
+        layout,
+        popup_rectangle: None,
+        exit: false,
+        last_activity: clock.now(),
+        fill_drift_start: (clock.now(), 0),
+        batches: Batches::default(),
+        clock,
+        last_popup_action: None,
+        protocol_error_reported: false,
+        last_toggle: None,
+        toggles: 0,
+        frame_pending: false,
+        wanted_popup: None,
+        last_buffer: None,
+        popups_opened: 0,
+        popup_created_at: None,
+        deactivate_at: None,
+        first_draw_scheduled: false,
+        popup_color: draw::FILL,
+        palette,
+        popup_heard_from: false,
+        keymap_hash: None,
+        versions,
+        scenario: scenario.into(),
+        scenario_failure: None,
+        watched_mtime: None,
+        candidates: config
+            .click_candidates
+            .as_deref()
+            .map_or(Vec::new(), |list| {
+                list.split(',').map(str::to_owned).collect()
+            }),
+        pointer: None,
+        pointer_y: None,
+        typing: None,
+        pool_logged_len: layout.stride * layout.height,
+        mirror,
+        warmup_keys: config.warmup_keys,
+        stressing: false,
+        awaiting_release: false,
+        checked_keymap: match (&config.keymap, config.check_keymap) {
+            (Some(path), true) => Some(fs::read_to_string(path)?),
+            _ => None,
+        },
+        auto_type: match &config.auto_type {
+            Some(text) => config.scancode_map.keystrokes(text)?,
+            None => Vec::new(),
+        },
     };
+    app.objects.created(app.surface.id());
+    app.objects.created(app.buffer.wl_buffer().id());
 
-    loop {
-        event_queue.blocking_dispatch(&mut app).unwrap();
+    event_loop
+        .handle()
+        .insert_source(
+            WaylandSource::new(conn.clone(), event_queue),
+            |_, queue, app| {
+                let events = queue.dispatch_pending(app)?;
+                if events > 0 {
+                    app.last_activity = app.clock.now();
+                    if app.config.batch_sizes {
+                        app.log_batch(events);
+                    }
+                }
+                Ok(events)
+            },
+        )
+        .map_err(|e| e.error)?;
+    if let Some(interval) = config.heartbeat {
+        let interval = Duration::from_secs(interval);
+        event_loop
+            .handle()
+            .insert_source(Timer::from_duration(interval), move |_, _, app| {
+                let deadline = next_heartbeat(app.last_activity, interval);
+                if app.clock.now() < deadline {
+                    return TimeoutAction::ToInstant(deadline);
+                }
+                println!("Heartbeat: {}", app.status());
+                app.last_activity = app.clock.now();
+                TimeoutAction::ToInstant(next_heartbeat(app.last_activity, interval))
+            })
+            .map_err(|e| e.error)?;
+    }
+    if !app.scenario.is_empty() {
+        let qh = qh.clone();
+        event_loop
+            .handle()
+            .insert_source(Timer::immediate(), move |_, _, app| app.run_scenario(&qh))
+            .map_err(|e| e.error)?;
     }
+    if let Some(path) = config.watch_file.clone() {
+        event_loop
+            .handle()
+            .insert_source(Timer::immediate(), move |_, _, app| {
+                app.check_watch_file(&path);
+                TimeoutAction::ToDuration(WATCH_INTERVAL)
+            })
+            .map_err(|e| e.error)?;
+    }
+    Ok((app, event_loop))
+}
+
+// Reads an xkb keymap into a memfd, as a NUL-terminated string.
+fn load_keymap(path: &Path) -> io::Result<(File, u32)> {
+    let mut keymap = fs::read(path)?;
+    if keymap.last() != Some(&0) {
+        keymap.push(0);
+    }
+    let mut file = File::from(rustix::fs::memfd_create("keymap", MemfdFlags::CLOEXEC)?);
+    file.write_all(&keymap)?;
+    Ok((file, keymap.len() as u32))
 }
 
 struct App {
+    loop_handle: LoopHandle<'static, App>,
+    config: Config,
+    exit_key: Option<u32>,
+    toggle_key: Option<u32>,
+    cancel_key: Option<u32>,
+    conn: Connection,
+    // Number of popup surface commits so far.
+    commits: usize,
+    // With --sync-commits, how many of them the compositor has processed.
+    synced_commits: usize,
+    // Number of frames drawn so far, which drives the animation.
+    frame: usize,
     registry_state: RegistryState,
     compositor: WlCompositor,
     shm_pool: SlotPool,
-    pending_active: bool,
+    // IME state from the compositor, which takes effect atomically on Done.
+    pending: ImeState,
+    current: ImeState,
+    // Done events so far. Our commits carry this as their serial.
+    done_count: u32,
+    // The serial of our last commit.
+    commit_serial: u32,
+    // Whether there are --commit-mode=batched strings still to commit.
+    uncommitted: bool,
+    // What we expect the surrounding text to be, given what we've committed.
+    text_model: Option<SurroundingText>,
     input_method: ZwpInputMethodV2,
-    virtual_keyboard: ZwpVirtualKeyboardV1,
+    // To get the input method again after it was unavailable.
+    im_mgr: ZwpInputMethodManagerV2,
+    seat: WlSeat,
+    // How often --wait-for-slot asks again.
+    slot_retry_interval: Duration,
+    // Absent with --no-vk, in which case keys only drive the popup.
+    virtual_keyboard: Option<Box<dyn KeySink>>,
     grabbed_keyboard: Option<GrabbedKeyboard>,
     open_popup: Option<OpenPopup>,
+    // Whether `open_popup` was hidden by --hide-mode=unmap.
+    popup_unmapped: bool,
     surface: WlSurface,
+    // Whether `surface` has ever been a popup. Roles are permanent, so reusing
+    // it for another popup is a protocol violation: the likely root cause.
+    surface_has_role: bool,
+    outputs: Vec<(WlOutput, Output)>,
+    // The outputs `surface` is on, per enter/leave events.
+    surface_outputs: Vec<WlOutput>,
+    // With --leak-check, every popup, surface and buffer we've created.
+    objects: Objects,
+    // Whether `surface` has a buffer committed.
+    buffer_attached: bool,
     buffer: Buffer,
+    // The animation frame `buffer` holds, if any.
+    buffer_frame: Option<usize>,
+    // Whether the current popup has had a frame drawn, not just a blank.
+    popup_drawn: bool,
+    // With --buffer-age-emulation, other buffers and the frames they hold.
+    spare_buffers: Vec<(Buffer, Option<usize>)>,
+    // The size `buffer` should have.
+    layout: Layout,
+    // Where the compositor says the text input is, relative to the popup.
+    popup_rectangle: Option<(i32, i32, i32, i32)>,
+    exit: bool,
+    // When we last received an event (or logged a heartbeat).
+    last_activity: Instant,
+    // Shared, so tests can keep a handle on a ManualClock.
+    clock: Rc<dyn Clock>,
+    // What we last did to a popup, which is what to blame for a protocol
+    // error.
+    last_popup_action: Option<&'static str>,
+    // Whether we've printed the protocol error already.
+    protocol_error_reported: bool,
+    last_toggle: Option<Instant>,
+    // How many keys have toggled the popup.
+    toggles: usize,
+    // Whether we've asked for a frame callback and not had it yet.
+    frame_pending: bool,
+    // With --toggle-per-frame, whether the popup should be shown once the
+    // pending frame callback arrives.
+    wanted_popup: Option<bool>,
+    // A copy of the last buffer we committed, for --dump-last-buffer.
+    last_buffer: Option<(Layout, Vec<u8>)>,
+    popups_opened: usize,
+    // When the latest popup was created, until its first frame callback.
+    popup_created_at: Option<Instant>,
+    // The frame at which to act out --deactivate-after.
+    deactivate_at: Option<usize>,
+    // Whether --delay-first-draw's timer has been set, which happens once.
+    first_draw_scheduled: bool,
+    // What the current popup fills up with.
+    popup_color: [u8; 4],
+    // The colors popups cycle through, if they do.
+    palette: Vec<[u8; 4]>,
+    // Whether the compositor has sent any event for the latest popup, which
+    // is our only sign that it was mapped.
+    popup_heard_from: bool,
+    // The last keymap we forwarded to the virtual keyboard.
+    keymap_hash: Option<u64>,
+    // Interface name and bound version, for --report.
+    versions: Vec<(&'static str, u32)>,
+    // Keys --auto-type has yet to start typing.
+    auto_type: Vec<u32>,
+    // For --ime-only: what keys type, once the grab has sent its keymap.
+    typing: Option<Typing>,
+    // The --scenario steps still to run, with their line numbers.
+    scenario: VecDeque<(usize, Step)>,
+    // Why the scenario failed, if it did.
+    scenario_failure: Option<String>,
+    // When the --watch-file was last changed, as far as we've seen.
+    watched_mtime: Option<SystemTime>,
+    // The --click-candidates, top to bottom.
+    candidates: Vec<String>,
+    pointer: Option<WlPointer>,
+    // Where the pointer is on the popup, if it's there.
+    pointer_y: Option<f64>,
+    // The shm pool's size when --pool-stats last logged it, or at startup.
+    pool_logged_len: usize,
+    mirror: Option<Mirror>,
+    // Key presses left before keys start toggling the popup.
+    warmup_keys: usize,
+    // The --keymap, if --check-keymap compares the grab's against it.
+    checked_keymap: Option<String>,
+    // Whether --stress is toggling the popup.
+    stressing: bool,
+    // Whether --shared-buffer is waiting to draw until the compositor
+    // releases the buffer.
+    awaiting_release: bool,
+    // When the latest popup opened, and the frame count then.
+    fill_drift_start: (Instant, usize),
+    // For --batch-sizes: how many events dispatches usually handle.
+    batches: Batches,
+}
+
+// Allows a toggle, and records it as the last, unless the last was less than
+// `delay` ago.
+fn debounce(last_toggle: &mut Option<Instant>, clock: &dyn Clock, delay: Duration) -> bool {
+    let now = clock.now();
+    if last_toggle.is_some_and(|last| now - last < delay) {
+        return false;
+    }
+    *last_toggle = Some(now);
+    true
+}
+
+// What the popup opened after `popups_opened` others fills up with. With
+// --color-cycle or --palette, a stale duplicate of an earlier popup shows an
+// older color. An empty --palette file leaves every popup the usual color.
+fn popup_color(config: &Config, palette: &[[u8; 4]], popups_opened: usize) -> [u8; 4] {
+    let cycling = config.color_cycle || config.palette.is_some();
+    match palette.len() {
+        len if cycling && len > 0 => palette[popups_opened % len],
+        _ => config.fill_color.map_or(draw::FILL, |c| c.0),
+    }
+}
+
+// Heartbeats are logged after this long without any events.
+fn next_heartbeat(last_activity: Instant, interval: Duration) -> Instant {
+    last_activity + interval
 }
 
 // Handle IME activation/deactivation by grabbing/releasing keyboard.
@@ -115,25 +882,141 @@ impl Dispatch<ZwpInputMethodV2, ()> for App {
         _: &Connection,
         qhandle: &QueueHandle<Self>,
     ) {
-        println!("{event:?}");
+        let _span = state.handling("zwp_input_method_v2");
+        crash::log_event(format!("{event:?}"));
         match event {
-            zwp_input_method_v2::Event::Activate => state.pending_active = true,
-            zwp_input_method_v2::Event::Deactivate => state.pending_active = false,
+            zwp_input_method_v2::Event::Activate => {
+                // Activation starts over with fresh state.
+                state.pending = ImeState {
+                    active: true,
+                    ..Default::default()
+                }
+            }
+            zwp_input_method_v2::Event::Deactivate => state.pending.active = false,
+            zwp_input_method_v2::Event::SurroundingText {
+                text,
+                cursor,
+                anchor,
+            } => state.pending.surrounding_text = Some(SurroundingText::new(text, cursor, anchor)),
+            zwp_input_method_v2::Event::Unavailable => state.unavailable(qhandle),
             zwp_input_method_v2::Event::Done => {
-                if state.pending_active {
-                    if state.grabbed_keyboard.is_none() {
-                        state.grabbed_keyboard =
-                            Some(GrabbedKeyboard(proxy.grab_keyboard(qhandle, ())));
+                state.done_count += 1;
+                state.apply_pending(proxy, qhandle);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ImeState {
+    active: bool,
+    surrounding_text: Option<SurroundingText>,
+}
+
+impl App {
+    // Makes the pending IME state current, and reacts to any changes.
+    fn apply_pending(&mut self, input_method: &ZwpInputMethodV2, qh: &QueueHandle<Self>) {
+        let changed = self.pending != self.current;
+        let previous = std::mem::replace(&mut self.current, self.pending.clone());
+        if let Some(received) = &self.current.surrounding_text {
+            if previous.surrounding_text.as_ref() != Some(received) {
+                match &self.text_model {
+                    Some(expected) if expected != received => {
+                        println!("Surrounding text: {received}, but expected {expected}")
                     }
-                } else {
-                    // Drop the grab if we have one.
-                    state.grabbed_keyboard = None;
+                    _ => println!("Surrounding text: {received}"),
                 }
             }
-            _ => {}
+        }
+        // The app's word is final, whether or not it matches ours.
+        self.text_model = self.current.surrounding_text.clone();
+        let was_held = self.grab_held();
+        if self.current.active {
+            if self.grabbed_keyboard.is_none() {
+                self.emit(input_method.id(), "grab_keyboard", format_args!(""));
+                self.grabbed_keyboard = Some(GrabbedKeyboard(input_method.grab_keyboard(qh, ())));
+            }
+        } else if self.config.sticky_grab {
+            if self.grab_held() {
+                warning!("--sticky-grab: keeping the grab while deactivated, against the protocol");
+            }
+        } else {
+            self.release_grab();
+        }
+        if self.grab_held() != was_held {
+            println!("Grab held: {}", self.grab_held());
+            if self.grab_held() && !self.auto_type.is_empty() {
+                self.start_auto_type(qh);
+            }
+        }
+        let activated = self.current.active && !previous.active;
+        if activated && self.config.spawn_on_activate && !self.popup_shown() {
+            println!("Showing popup on activation");
+            self.show_popup(qh);
+        }
+        if activated && self.config.stress && !self.stressing {
+            self.stressing = true;
+            self.stress_toggle(qh);
+        }
+        if changed || self.uncommitted {
+            self.commit_changes();
+        } else {
+            // A commit here would only be noise for the compositor.
+            println!("Done without changes, not committing");
+        }
+    }
+
+    // For --stress: toggles the popup, and again on every turn of the event
+    // loop while we're active.
+    fn stress_toggle(&mut self, qh: &QueueHandle<Self>) {
+        if self.exit || !self.current.active {
+            self.stressing = false;
+            return;
+        }
+        self.toggles += 1;
+        self.set_popup(!self.popup_shown(), qh);
+        let qh = qh.clone();
+        self.loop_handle
+            .insert_idle(move |app| app.stress_toggle(&qh));
+    }
+
+    // For --frames: shuts down once enough frames are drawn.
+    fn check_frame_limit(&mut self) {
+        if !self.exit
+            && self
+                .config
+                .frames
+                .is_some_and(|frames| self.frame >= frames)
+        {
+            println!("Reached --frames");
+            self.shutdown();
         }
     }
+
+    fn release_grab(&mut self) {
+        if let Some(grab) = self.grabbed_keyboard.take() {
+            self.emit(grab.0.id(), "release", format_args!(""));
+        }
+    }
+
+    fn grab_held(&self) -> bool {
+        self.grabbed_keyboard.is_some()
+    }
+}
+
+// What became of a key event. A toggle may also have been forwarded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyAction {
+    ToggledOn,
+    ToggledOff,
+    ForwardedOnly,
+    // Typed with commit_string, for --ime-only.
+    CommittedOnly,
+    // Neither toggled nor passed on.
+    Consumed,
 }
+
 struct GrabbedKeyboard(ZwpInputMethodKeyboardGrabV2);
 impl Drop for GrabbedKeyboard {
     fn drop(&mut self) {
@@ -151,7 +1034,8 @@ impl Dispatch<ZwpInputMethodKeyboardGrabV2, ()> for App {
         _: &Connection,
         qhandle: &QueueHandle<Self>,
     ) {
-        println!("{event:?}");
+        let _span = app.handling("zwp_input_method_keyboard_grab_v2");
+        crash::log_event(format!("{event:?}"));
         match event {
             zwp_input_method_keyboard_grab_v2::Event::Key {
                 serial: _,
@@ -159,36 +1043,43 @@ impl Dispatch<ZwpInputMethodKeyboardGrabV2, ()> for App {
                 key,
                 state,
             } => {
-                // On each keystroke, toggle the popup visibility.
-                if state == WEnum::Value(wl_keyboard::KeyState::Pressed) {
-                    if app.open_popup.is_some() {
-                        app.open_popup = None
-                    } else {
-                        if false {
-                            // ENABLE THIS AND EVERYTHING WORKS!
-                            app.surface.destroy();
-                            app.surface = app.compositor.create_surface(qhandle, ());
-                        }
-                        app.open_popup = Some(OpenPopup(app.input_method.get_input_popup_surface(
-                            &app.surface,
-                            qhandle,
-                            (),
-                        )));
-                        draw(
-                            &mut app.buffer,
-                            &mut app.surface,
-                            &mut app.shm_pool,
-                            qhandle,
-                        );
-                    }
-                }
-                // Also pass the keystroke through to the app via VK.
-                app.virtual_keyboard.key(time, key, state.into());
+                app.handle_key(time, key, state, qhandle);
             }
 
             // Pass other events through to the app via VK.
             zwp_input_method_keyboard_grab_v2::Event::Keymap { format, fd, size } => {
-                app.virtual_keyboard.keymap(format.into(), fd.as_fd(), size);
+                let keymap = File::from(fd);
+                if let Some(ours) = &app.checked_keymap {
+                    match read_keymap(&keymap, size) {
+                        Ok(text) => check_keymap(&String::from_utf8_lossy(&text), ours),
+                        Err(e) => warning!("can't read the grab's keymap: {e}"),
+                    }
+                }
+                if app.config.ime_only {
+                    let text = read_keymap(&keymap, size).map_err(|e| e.to_string());
+                    match text.and_then(|text| Typing::new(&String::from_utf8_lossy(&text))) {
+                        Ok(typing) => app.typing = Some(typing),
+                        Err(e) => warning!("can't use the grab's keymap: {e}"),
+                    }
+                }
+                if app.config.override_keymap {
+                    println!("Not forwarding the compositor's keymap (--override-keymap)");
+                } else if let Some(vk) = &app.virtual_keyboard {
+                    // Regrabs resend the same keymap, which the app needn't see again.
+                    let hash = hash_keymap(&keymap, size).ok();
+                    if hash.is_some() && hash == app.keymap_hash {
+                        println!("Keymap unchanged, not resending");
+                    } else {
+                        app.keymap_hash = hash;
+                        let format = format.into();
+                        app.emit(
+                            vk.object(),
+                            "keymap",
+                            format_args!("{format}, <fd>, {size}"),
+                        );
+                        vk.keymap(format, keymap.as_fd(), size);
+                    }
+                }
             }
             zwp_input_method_keyboard_grab_v2::Event::Modifiers {
                 serial: _,
@@ -197,102 +1088,2979 @@ impl Dispatch<ZwpInputMethodKeyboardGrabV2, ()> for App {
                 mods_locked,
                 group,
             } => {
-                app.virtual_keyboard
-                    .modifiers(mods_depressed, mods_latched, mods_locked, group);
+                if let Some(typing) = &mut app.typing {
+                    typing.set_modifiers(mods_depressed, mods_latched, mods_locked, group);
+                }
+                if let Some(vk) = &app.virtual_keyboard {
+                    app.emit(
+                        vk.object(),
+                        "modifiers",
+                        format_args!("{mods_depressed}, {mods_latched}, {mods_locked}, {group}"),
+                    );
+                    vk.modifiers(mods_depressed, mods_latched, mods_locked, group);
+                }
             }
 
             _ => {}
         }
     }
 }
-struct OpenPopup(ZwpInputPopupSurfaceV2);
-impl Drop for OpenPopup {
-    fn drop(&mut self) {
-        self.0.destroy();
-    }
-}
 
-// Drawing and buffer management.
-pub fn draw_into(data: &mut [u8]) {
-    static DRAW_COUNT: AtomicUsize = AtomicUsize::new(0);
-    let count = DRAW_COUNT.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
-
-    const RED: [u8; 4] = [0u8, 0, 255, 255];
-    const BLUE: [u8; 4] = [255u8, 0, 0, 255];
-    for (index, pix) in data.chunks_exact_mut(4).enumerate() {
-        pix.copy_from_slice(if index / WIDTH < count / 10 {
-            &RED
-        } else {
-            &BLUE
-        });
-    }
+// What a grabbed key is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyRole {
+    // The --exit-key and --cancel-key, which the app doesn't see.
+    Exit,
+    Cancel,
+    // Toggles the popup. The --toggle-key is kept from the app; without one,
+    // every key toggles and is passed on.
+    Toggle { forward: bool },
+    // Just passed on, as there's a --toggle-key and this isn't it.
+    Forward,
 }
 
-fn draw(buffer: &mut Buffer, surface: &WlSurface, shm: &mut SlotPool, qh: &QueueHandle<App>) {
-    if let Some(data) = buffer.canvas(shm) {
-        draw_into(data);
+fn key_role(
+    key: u32,
+    exit_key: Option<u32>,
+    cancel_key: Option<u32>,
+    toggle_key: Option<u32>,
+) -> KeyRole {
+    if Some(key) == exit_key {
+        KeyRole::Exit
+    } else if Some(key) == cancel_key {
+        KeyRole::Cancel
+    } else if toggle_key.is_none() {
+        KeyRole::Toggle { forward: true }
+    } else if Some(key) == toggle_key {
+        KeyRole::Toggle { forward: false }
     } else {
-        let (newbuf, data) = create_buffer(shm);
-        draw_into(data);
-        *buffer = newbuf;
-    };
-    buffer.attach_to(surface).expect("attach");
-    surface.damage_buffer(0, 0, WIDTH as i32, HEIGHT as i32);
-    surface.frame(qh, ());
-    surface.commit();
+        KeyRole::Forward
+    }
 }
 
-impl Dispatch<WlCallback, ()> for App {
-    fn event(
-        state: &mut Self,
-        _: &WlCallback,
-        _: wl_callback::Event,
-        _: &(),
-        _: &Connection,
-        qhandle: &QueueHandle<Self>,
-    ) {
-        draw(
-            &mut state.buffer,
-            &mut state.surface,
-            &mut state.shm_pool,
-            qhandle,
-        )
-    }
+// Counts off one of the --warmup-keys, if any are left.
+fn warming_up(keys_left: &mut usize) -> bool {
+    let warming = *keys_left > 0;
+    *keys_left = keys_left.saturating_sub(1);
+    warming
 }
 
-fn create_buffer(shm: &mut SlotPool) -> (Buffer, &mut [u8]) {
-    shm.create_buffer(
-        WIDTH as i32,
-        HEIGHT as i32,
-        (WIDTH * 4) as i32,
-        wl_shm::Format::Argb8888,
-    )
-    .expect("create buffer")
+// The time since `start`, if it's set, which it then isn't: for latencies
+// that are reported once.
+fn take_elapsed(start: &mut Option<Instant>, clock: &dyn Clock) -> Option<Duration> {
+    let start = start.take()?;
+    Some(clock.now().saturating_duration_since(start))
 }
 
-// Dumb framework boilerplate.
-delegate_registry!(App);
-delegate_noop!(App: ignore ZwpInputMethodManagerV2);
-delegate_noop!(App: ignore ZwpVirtualKeyboardManagerV1);
-delegate_noop!(App: ignore ZwpVirtualKeyboardV1);
-delegate_noop!(App: ignore WlSeat);
-delegate_noop!(App: ignore WlCompositor);
-delegate_noop!(App: ignore WlSurface);
-delegate_noop!(App: ignore ZwpInputPopupSurfaceV2);
-delegate_noop!(App: ignore WlShm);
-delegate_noop!(App: ignore WlBuffer);
+impl App {
+    fn handle_key(
+        &mut self,
+        time: u32,
+        key: u32,
+        state: WEnum<wl_keyboard::KeyState>,
+        qh: &QueueHandle<Self>,
+    ) -> KeyAction {
+        let action = self.route_key(time, key, state, qh);
+        let pressed = state == WEnum::Value(wl_keyboard::KeyState::Pressed);
+        let state = if pressed { "pressed" } else { "released" };
+        println!("Key {key} {state}: {action:?}");
+        action
+    }
 
-impl ProvidesRegistryState for App {
-    fn registry(&mut self) -> &mut RegistryState {
-        &mut self.registry_state
+    fn route_key(
+        &mut self,
+        time: u32,
+        key: u32,
+        state: WEnum<wl_keyboard::KeyState>,
+        qh: &QueueHandle<Self>,
+    ) -> KeyAction {
+        let pressed = state == WEnum::Value(wl_keyboard::KeyState::Pressed);
+        let role = key_role(key, self.exit_key, self.cancel_key, self.toggle_key);
+        match role {
+            KeyRole::Exit => {
+                // Swallow the key entirely, so the app doesn't see half a keypress.
+                if pressed {
+                    self.shutdown();
+                }
+                return KeyAction::Consumed;
+            }
+            KeyRole::Cancel => {
+                if pressed && self.popup_shown() {
+                    self.toggles += 1;
+                    self.set_popup(false, qh);
+                    return KeyAction::ToggledOff;
+                }
+                return KeyAction::Consumed;
+            }
+            KeyRole::Toggle { .. } | KeyRole::Forward => {}
+        }
+        if pressed {
+            if let Some(text) = self.config.commit_string_on_toggle.clone() {
+                self.commit_text(&text);
+            }
+        }
+        // On each keystroke (or just the --toggle-key), toggle the popup
+        // visibility.
+        let mut action = KeyAction::Consumed;
+        if pressed && warming_up(&mut self.warmup_keys) {
+            println!("Warmup key, {} to go", self.warmup_keys);
+        } else if pressed && matches!(role, KeyRole::Toggle { .. }) && self.debounced() {
+            self.toggles += 1;
+            let shown = if self.config.toggle_per_frame && self.frame_pending {
+                // Toggles what we'll show, not what's shown now.
+                let wanted = !self.wanted_popup.unwrap_or(self.popup_shown());
+                self.wanted_popup = Some(wanted);
+                let shown = if wanted { "shown" } else { "hidden" };
+                println!("key {}: popup -> {shown} next frame", self.toggles);
+                wanted
+            } else {
+                self.set_popup(!self.popup_shown(), qh);
+                self.popup_shown()
+            };
+            action = if shown {
+                KeyAction::ToggledOn
+            } else {
+                KeyAction::ToggledOff
+            };
+        }
+        // As with forwarding, the --toggle-key is kept to ourselves.
+        if self.config.ime_only {
+            let typed = self.typing.as_ref().and_then(|typing| typing.text(key));
+            if self.typing.is_none() && pressed {
+                warning!("no keymap from the grab yet, so key {key} types nothing");
+            }
+            match typed {
+                Some(text) if pressed && Some(key) != self.toggle_key => {
+                    self.commit_text(&text);
+                    if action == KeyAction::Consumed {
+                        action = KeyAction::CommittedOnly;
+                    }
+                }
+                _ => {}
+            }
+            return action;
+        }
+        // Also pass the keystroke through to the app via VK.
+        if role == (KeyRole::Toggle { forward: false }) || !self.config.forward_on.forwards(pressed)
+        {
+            return action;
+        }
+        if let Some(vk) = &self.virtual_keyboard {
+            let state_code = u32::from(pressed);
+            self.emit(
+                vk.object(),
+                "key",
+                format_args!("{time}, {key}, {state_code}"),
+            );
+            vk.key(time, key, state.into());
+            if action == KeyAction::Consumed {
+                action = KeyAction::ForwardedOnly;
+            }
+        }
+        action
     }
 
-    registry_handlers!();
-}
+    // Sends `text` to the app, committing as --commit-mode says.
+    fn commit_text(&mut self, text: &str) {
+        self.emit(
+            self.input_method.id(),
+            "commit_string",
+            format_args!("{text:?}"),
+        );
+        self.input_method.commit_string(text.to_owned());
+        if let Some(model) = &mut self.text_model {
+            model.commit(text);
+            println!("Surrounding text should become {model}");
+        }
+        match self.config.commit_mode {
+            CommitMode::PerKey => self.commit_changes(),
+            // Left for the commit on the next Done.
+            CommitMode::Batched => self.uncommitted = true,
+        }
+    }
 
-struct Provider<T>(T);
-impl<T: Proxy, const N: u32> ProvidesBoundGlobal<T, N> for Provider<T> {
-    fn bound_global(&self) -> Result<T, smithay_client_toolkit::error::GlobalError> {
-        Ok(self.0.clone())
+    // Types the --auto-type keys, once, as press and release events at a
+    // steady pace. Any still due after the grab is lost are dropped, as a
+    // real keyboard's would be.
+    fn start_auto_type(&mut self, qh: &QueueHandle<Self>) {
+        println!("Auto-typing {} keys", self.auto_type.len());
+        let mut at = Duration::ZERO;
+        for key in mem::take(&mut self.auto_type) {
+            for (delay, state) in [
+                (at, wl_keyboard::KeyState::Pressed),
+                (at + KEY_HOLD, wl_keyboard::KeyState::Released),
+            ] {
+                let qh = qh.clone();
+                self.loop_handle
+                    .insert_source(Timer::from_duration(delay), move |_, _, app| {
+                        if app.grab_held() {
+                            app.handle_key(delay.as_millis() as u32, key, WEnum::Value(state), &qh);
+                        }
+                        TimeoutAction::Drop
+                    })
+                    .expect("insert timer");
+            }
+            at += TYPING_INTERVAL;
+        }
+    }
+
+    // Another input method took over, or the compositor is tearing down.
+    // Only one input method per seat, so unless --wait-for-slot says to wait
+    // for the other one to go, there's nothing left for us to do.
+    fn unavailable(&mut self, qh: &QueueHandle<Self>) {
+        if !self.config.wait_for_slot {
+            println!("Input method unavailable (another one may be active, try --wait-for-slot)");
+            self.shutdown();
+            return;
+        }
+        let interval = self.slot_retry_interval;
+        println!("Input method unavailable, retrying in {interval:?}");
+        let qh = qh.clone();
+        self.loop_handle
+            .insert_source(Timer::from_duration(interval), move |_, _, app| {
+                // The old one is inert, but still ours to destroy.
+                app.emit(app.input_method.id(), "destroy", format_args!(""));
+                app.input_method.destroy();
+                app.input_method = app.im_mgr.get_input_method(&app.seat, &qh, ());
+                app.emit(
+                    app.im_mgr.id(),
+                    "get_input_method",
+                    format_args!("{}, {}", app.seat.id(), app.input_method.id()),
+                );
+                app.pending = ImeState::default();
+                app.current = ImeState::default();
+                app.done_count = 0;
+                app.commit_serial = 0;
+                TimeoutAction::Drop
+            })
+            .expect("insert timer");
+    }
+
+    fn set_popup(&mut self, shown: bool, qh: &QueueHandle<Self>) {
+        if shown {
+            self.show_popup(qh);
+        } else {
+            self.hide_popup();
+        }
+        println!("{}", self.toggle_result());
+    }
+
+    // Whether enough time has passed since the last toggle to allow another.
+    fn debounced(&mut self) -> bool {
+        let allowed = debounce(
+            &mut self.last_toggle,
+            &*self.clock,
+            self.config.toggle_delay,
+        );
+        if !allowed {
+            println!("Ignoring toggle within --toggle-delay");
+        }
+        allowed
+    }
+
+    fn hide_popup(&mut self) {
+        // Given the crash on deactivate, be sure of the teardown order: once
+        // deactivated, the grab is gone before any popup is destroyed.
+        if !self.current.active && self.grab_held() && !self.config.sticky_grab {
+            warning!("destroying popup while deactivated but still holding the grab");
+        }
+        match self.config.hide_mode {
+            HideMode::Destroy => self.destroy_popup(),
+            HideMode::Unmap => {
+                self.emit(self.surface.id(), "attach", format_args!("nil, 0, 0"));
+                self.surface.attach(None, 0, 0);
+                self.emit(self.surface.id(), "commit", format_args!(""));
+                self.surface.commit();
+                self.commits += 1;
+                self.buffer_attached = false;
+                self.popup_unmapped = true;
+            }
+        }
+        // The surface is unmapped, so its frame callback may never come.
+        self.frame_pending = false;
+        self.check_protocol_error("hiding popup");
+    }
+
+    fn replace_surface(&mut self, qh: &QueueHandle<Self>) {
+        self.emit(self.surface.id(), "destroy", format_args!(""));
+        self.surface.destroy();
+        self.surface = create_surface(&self.compositor, qh, &self.config);
+        self.emit(
+            self.compositor.id(),
+            "create_surface",
+            format_args!("{}", self.surface.id()),
+        );
+        self.surface_has_role = false;
+        self.surface_outputs.clear();
+        self.buffer_attached = false;
+        self.popup_unmapped = false;
+        self.objects.created(self.surface.id());
+        if self.config.shared_buffer {
+            // The new surface gets the very same wl_buffer.
+            return;
+        }
+        self.buffer = create_buffer(&mut self.shm_pool, self.layout).0;
+        self.buffer_frame = None;
+        self.objects.created(self.buffer.wl_buffer().id());
+    }
+
+    // The output is gone, and the popup may have been on it. Start over with
+    // a brand new popup and surface, for the compositor to place elsewhere.
+    fn output_removed(&mut self, global: u32, qh: &QueueHandle<Self>) {
+        let Some(index) = self
+            .outputs
+            .iter()
+            .position(|(_, info)| info.global == global)
+        else {
+            return;
+        };
+        let name = self.output_name(&self.outputs[index].0);
+        let (output, _) = self.outputs.remove(index);
+        println!("Output {name} removed");
+        let was_on_it = self.surface_outputs.contains(&output);
+        self.surface_outputs.retain(|o| *o != output);
+        if output.version() >= 3 {
+            output.release();
+        }
+        if was_on_it && self.popup_shown() {
+            println!("Recreating the popup, which was on {name}");
+            self.hide_popup();
+            self.destroy_popup();
+            self.replace_surface(qh);
+            self.show_popup(qh);
+        }
+    }
+
+    // Whether the popup is up. With --hide-mode=unmap it may exist but be
+    // unmapped.
+    fn popup_shown(&self) -> bool {
+        self.open_popup.is_some() && !self.popup_unmapped
+    }
+
+    fn show_popup(&mut self, qh: &QueueHandle<Self>) {
+        if self.popup_unmapped {
+            // Attaching a buffer maps the same popup again.
+            self.popup_unmapped = false;
+            self.draw(qh);
+            self.check_protocol_error("remapping popup");
+            return;
+        }
+        if self.config.workaround {
+            // With a fresh surface (and buffer) for each popup, everything works!
+            self.replace_surface(qh);
+        }
+        if self.surface_has_role {
+            warning!(
+                "reusing {} for a new popup, but it already has the popup role",
+                self.surface.id()
+            );
+        }
+        self.surface_has_role = true;
+        self.popup_color = popup_color(&self.config, &self.palette, self.popups_opened);
+        self.emit(
+            self.input_method.id(),
+            "get_input_popup_surface",
+            format_args!("{}", self.surface.id()),
+        );
+        self.open_popup = Some(OpenPopup(self.input_method.get_input_popup_surface(
+            &self.surface,
+            qh,
+            (),
+        )));
+        if let Some(popup) = &self.open_popup {
+            self.objects.created(popup.0.id());
+        }
+        // Don't let stale pixels from the last popup leak into this one: the
+        // first commit is blank, and the animation starts on the next frame.
+        // The very first popup starts with --initial-color, if given.
+        let blank = self.config.clear_on_open
+            || (self.config.initial_color.is_some() && self.popups_opened == 0);
+        // Its color, or a cleared buffer, means no frame can be reused.
+        self.forget_buffer_frames();
+        self.popup_drawn = false;
+        self.popup_created_at = Some(self.clock.now());
+        self.fill_drift_start = (self.clock.now(), self.frame);
+        match self.config.delay_first_draw {
+            Some(delay) if !self.first_draw_scheduled => {
+                self.first_draw_scheduled = true;
+                // Counted below.
+                let popup = self.popups_opened + 1;
+                let qh = qh.clone();
+                self.loop_handle
+                    .insert_source(Timer::from_duration(delay), move |_, _, app| {
+                        // Later popups draw as usual, even if this one is gone.
+                        if app.popups_opened == popup && app.popup_shown() {
+                            println!("Drawing first popup after {delay:?}");
+                            app.paint(&qh, blank);
+                        }
+                        TimeoutAction::Drop
+                    })
+                    .expect("insert timer");
+            }
+            _ => self.paint(qh, blank),
+        }
+        for _ in 0..self.config.recommit {
+            self.emit(self.surface.id(), "commit", format_args!(""));
+            self.surface.commit();
+            self.commits += 1;
+        }
+        self.check_protocol_error("showing popup");
+
+        self.popups_opened += 1;
+        self.popup_heard_from = false;
+        if let (Some(frames), 2) = (self.config.deactivate_after, self.popups_opened) {
+            self.deactivate_at = Some(self.frame + frames);
+        }
+        if let Some(timeout) = self.config.map_timeout {
+            let popup = self.popups_opened;
+            self.loop_handle
+                .insert_source(Timer::from_duration(timeout), move |_, _, app| {
+                    // Only the latest popup matters, earlier ones are gone.
+                    if app.popups_opened == popup && !app.popup_heard_from {
+                        warning!("popup #{popup} not mapped after {timeout:?}");
+                    }
+                    TimeoutAction::Drop
+                })
+                .expect("insert timer");
+        }
+    }
+
+    // What a deactivate from the compositor would do, but on our schedule.
+    fn simulate_deactivate(&mut self, qh: &QueueHandle<Self>) {
+        self.deactivate_at = None;
+        self.pending.active = false;
+        let input_method = self.input_method.clone();
+        self.apply_pending(&input_method, qh);
+        if self.popup_shown() {
+            self.hide_popup();
+        }
+    }
+
+    // Rereads the --watch-file if it changed, to be used from the next draw.
+    // A bad file is reported and changes nothing.
+    fn check_watch_file(&mut self, path: &Path) {
+        let mtime = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        if mtime.is_none() || mtime == self.watched_mtime {
+            return;
+        }
+        self.watched_mtime = mtime;
+        let mut config = self.config.clone();
+        let result = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| config.set_draw_params(&text));
+        if let Err(e) = result {
+            warning!("not reloading {}: {e}", path.display());
+            return;
+        }
+        println!("Reloaded draw parameters from {}", path.display());
+        // The current popup's color, which the reload may have changed.
+        let popup = self.popups_opened.saturating_sub(1);
+        self.popup_color = popup_color(&config, &self.palette, popup);
+        self.config = config;
+        // What's in our buffers no longer matches their frame numbers.
+        self.forget_buffer_frames();
+    }
+
+    // Runs --scenario steps up to the next wait, and says when to carry on.
+    fn run_scenario(&mut self, qh: &QueueHandle<Self>) -> TimeoutAction {
+        while let Some((line, step)) = self.scenario.pop_front() {
+            println!("Scenario line {line}: {step:?}");
+            match step {
+                Step::Activate => {
+                    self.pending.active = true;
+                    let input_method = self.input_method.clone();
+                    self.apply_pending(&input_method, qh);
+                }
+                Step::Deactivate => self.simulate_deactivate(qh),
+                Step::Key(key) => {
+                    for state in [
+                        wl_keyboard::KeyState::Pressed,
+                        wl_keyboard::KeyState::Released,
+                    ] {
+                        self.handle_key(0, key, WEnum::Value(state), qh);
+                    }
+                }
+                Step::Wait(duration) => return TimeoutAction::ToDuration(duration),
+                Step::ExpectPopups(n) if n != self.popups_opened => {
+                    let failure = format!(
+                        "line {line}: expected {n} popups, got {}",
+                        self.popups_opened
+                    );
+                    println!("Scenario failed at {failure}");
+                    self.scenario_failure = Some(failure);
+                    self.shutdown();
+                    return TimeoutAction::Drop;
+                }
+                Step::ExpectPopups(_) => {}
+            }
+            if self.exit {
+                return TimeoutAction::Drop;
+            }
+        }
+        println!("Scenario done");
+        self.shutdown();
+        TimeoutAction::Drop
+    }
+
+    // Protocol errors usually only surface when the next dispatch fails, and
+    // then run() reports them. We check after each popup request too, in
+    // case the error has already been read.
+    fn check_protocol_error(&mut self, action: &'static str) {
+        self.last_popup_action = Some(action);
+        let err = self.conn.protocol_error();
+        if let Some(report) = protocol_error_report(err.as_ref(), self.last_popup_action) {
+            eprintln!("{report}");
+            self.protocol_error_reported = true;
+        }
+    }
+
+    // Applies our pending requests (e.g. commit_string) to the text input.
+    // Committing on each Done also keeps the compositor up to date with our
+    // serial.
+    fn commit_changes(&mut self) {
+        // The compositor uses the serial to discard commits based on stale
+        // state, so it can never go backwards.
+        debug_assert_eq!(check_serial(self.commit_serial, self.done_count), Ok(()));
+        self.commit_serial = self.done_count;
+        self.uncommitted = false;
+        let serial = self.commit_serial;
+        self.emit(self.input_method.id(), "commit", format_args!("{serial}"));
+        self.input_method.commit(serial);
+    }
+
+    // What the latest toggle did, numbered like the keystrokes in the
+    // description of the bug.
+    fn toggle_result(&self) -> String {
+        let live = usize::from(self.popup_shown());
+        let shown = if live > 0 { "shown" } else { "hidden" };
+        format!("key {}: popup -> {shown} ({live} live)", self.toggles)
+    }
+
+    // For --log-requests: our side of the conversation, logged as we go.
+    fn emit(&self, object: impl fmt::Display, request: &str, args: fmt::Arguments) {
+        log_request(&self.config, object, request, args);
+    }
+
+    // Destroys the popup object, if there is one.
+    fn destroy_popup(&mut self) {
+        if let Some(popup) = self.open_popup.take() {
+            self.emit(popup.0.id(), "destroy", format_args!(""));
+        }
+    }
+
+    // Every event handler starts here, so the clock sees each event.
+    fn handling(&self, name: &'static str) -> Span {
+        self.clock.tick();
+        Span::new(self.config.trace_timing, name, &self.clock)
+    }
+
+    // A one-line summary of the state we care about.
+    fn status(&self) -> String {
+        format!(
+            "active={} grab_held={} popup={} frame={} commits={} rectangle={:?} outputs={:?}",
+            self.current.active,
+            self.grab_held(),
+            self.open_popup.is_some(),
+            self.frame,
+            self.commits,
+            self.popup_rectangle,
+            self.surface_outputs
+                .iter()
+                .map(|output| self.output_name(output))
+                .collect::<Vec<_>>()
+        )
+    }
+
+    fn output_name(&self, output: &WlOutput) -> String {
+        self.outputs
+            .iter()
+            .find(|(o, _)| o == output)
+            .and_then(|(_, info)| info.name.clone())
+            .unwrap_or_else(|| output.id().to_string())
+    }
+
+    // Client-initiated teardown, in protocol order: release the grab, destroy
+    // the popup, then the input method itself. Contrast with the crash on
+    // compositor-initiated deactivate.
+    fn shutdown(&mut self) {
+        println!("Shutting down");
+        self.release_grab();
+        self.destroy_popup();
+        self.popup_unmapped = false;
+        self.mirror = None;
+        self.emit(self.input_method.id(), "destroy", format_args!(""));
+        self.input_method.destroy();
+        self.exit = true;
+    }
+}
+
+// What to print about a protocol error, given what we last did to a popup.
+fn protocol_error_report(err: Option<&ProtocolError>, last_action: Option<&str>) -> Option<String> {
+    let err = err?;
+    let after = last_action.map_or(String::new(), |action| format!(" after {action}"));
+    Some(format!(
+        "Protocol error{after}: {}@{} code {}: {}",
+        err.object_interface, err.object_id, err.code, err.message
+    ))
+}
+
+// Whether `next` may follow `last` as the serial of a commit.
+fn check_serial(last: u32, next: u32) -> Result<(), String> {
+    if next < last {
+        return Err(format!("commit serial went from {last} to {next}"));
+    }
+    Ok(())
+}
+
+// The compositor tells each popup where the text input is.
+impl Dispatch<ZwpInputPopupSurfaceV2, ()> for App {
+    fn event(
+        app: &mut Self,
+        _: &ZwpInputPopupSurfaceV2,
+        event: zwp_input_popup_surface_v2::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let _span = app.handling("zwp_input_popup_surface_v2");
+        crash::log_event(format!("{event:?}"));
+        app.popup_heard_from = true;
+        if let zwp_input_popup_surface_v2::Event::TextInputRectangle {
+            x,
+            y,
+            width,
+            height,
+        } = event
+        {
+            app.popup_rectangle = Some((x, y, width, height));
+            if app.config.fit_rectangle && width > 0 && height > 0 {
+                // --stride was only checked against our own width.
+                let stride = app.config.stride.unwrap_or(usize::MAX);
+                if stride >= width as usize * 4 {
+                    // Takes effect on the next draw.
+                    app.layout = Layout::new(width as usize, height as usize, app.config.stride);
+                } else {
+                    warning!("text input rectangle is too wide for --stride, not fitting it");
+                }
+            }
+        }
+    }
+}
+
+// Reads from the start whatever the file offset, as the compositor may send us
+// the same file each time.
+// For --check-keymap: warns if the grab's keymap and ours type different
+// things.
+fn check_keymap(grab: &str, ours: &str) {
+    match keymap_check::differences(grab, ours) {
+        Ok(None) => println!("The grab's keymap types the same as --keymap"),
+        Ok(Some(differences)) => {
+            warning!("--keymap doesn't match the grab's keymap: {differences}")
+        }
+        Err(e) => warning!("can't check the grab's keymap: {e}"),
+    }
+}
+
+fn read_keymap(keymap: &File, size: u32) -> io::Result<Vec<u8>> {
+    let mut contents = vec![0; size as usize];
+    keymap.read_exact_at(&mut contents, 0)?;
+    Ok(contents)
+}
+
+fn hash_keymap(keymap: &File, size: u32) -> io::Result<u64> {
+    let contents = read_keymap(keymap, size)?;
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    Ok(hasher.finish())
+}
+
+struct OpenPopup(ZwpInputPopupSurfaceV2);
+impl Drop for OpenPopup {
+    fn drop(&mut self) {
+        self.0.destroy();
+    }
+}
+
+// Drawing and buffer management.
+impl App {
+    fn draw(&mut self, qh: &QueueHandle<App>) {
+        self.paint(qh, false);
+    }
+
+    // Commits the next animation frame, or if `blank` just the background.
+    fn paint(&mut self, qh: &QueueHandle<App>, blank: bool) {
+        if self.config.buffer_age_emulation {
+            self.pick_aged_buffer();
+        }
+        let (layout, config, frame) = (self.layout, &self.config, self.frame);
+        let color = self.popup_color;
+        let reusable = self.buffer.height() as usize == layout.height
+            && self.buffer.stride() as usize == layout.stride
+            && !config.fresh_buffer_each_frame;
+        let data = match self.buffer.canvas(&mut self.shm_pool) {
+            Some(data) if reusable => data,
+            // --shared-buffer: the compositor may still be reading it for
+            // another surface, so leave it be until it's released.
+            None if reusable && config.shared_buffer => {
+                if !self.awaiting_release {
+                    println!("Shared buffer still in use, drawing once it's released");
+                    self.awaiting_release = true;
+                    self.redraw_on_release(qh);
+                }
+                return;
+            }
+            _ => {
+                let (newbuf, data) = create_buffer(&mut self.shm_pool, layout);
+                self.objects.created(newbuf.wl_buffer().id());
+                self.buffer = newbuf;
+                self.buffer_frame = None;
+                data
+            }
+        };
+        let mut rows = 0..layout.height;
+        if blank {
+            draw::clear(data, config);
+            self.buffer_frame = None;
+        } else if config.mark_first_frame && !config.static_fill && !self.popup_drawn {
+            draw::stripes(data, layout);
+            // Not an animation frame that later ones can be drawn over.
+            self.buffer_frame = None;
+        } else {
+            if let (Some(old), true) = (self.buffer_frame, config.buffer_age_emulation) {
+                rows = draw::changed_rows(layout, config, old, frame);
+            }
+            draw::draw_rows(data, layout, config, frame, color, rows.clone());
+            self.buffer_frame = Some(frame);
+        }
+        if config.dump_last_buffer.is_some() {
+            self.last_buffer = Some((layout, data.to_vec()));
+        }
+        if !blank {
+            self.popup_drawn = true;
+            self.frame += 1;
+        }
+        let mirrored = self.mirror.is_some().then(|| data.to_vec());
+        let buffer = self.buffer.wl_buffer().id();
+        self.emit(self.surface.id(), "attach", format_args!("{buffer}, 0, 0"));
+        self.buffer.attach_to(&self.surface).expect("attach");
+        self.damage_checked(0, rows.start as i32, layout.width as i32, rows.len() as i32);
+        if !self.config.no_frame_callback {
+            if self.config.sync_driven_draw {
+                // Handled just like a frame callback, but fires as soon as
+                // the compositor gets to it, shown or not.
+                self.emit(self.conn.display().id(), "sync", format_args!(""));
+                self.conn.display().sync(qh, self.surface.clone());
+            } else {
+                self.emit(self.surface.id(), "frame", format_args!(""));
+                self.surface.frame(qh, self.surface.clone());
+            }
+            self.frame_pending = true;
+        }
+        self.emit(self.surface.id(), "commit", format_args!(""));
+        self.surface.commit();
+        self.buffer_attached = true;
+        if let (Some(mirror), Some(data)) = (&mut self.mirror, mirrored) {
+            mirror.show(&mut self.shm_pool, layout, data);
+        }
+
+        self.commits += 1;
+        if self.config.sync_commits {
+            self.emit(self.conn.display().id(), "sync", format_args!(""));
+            self.conn.display().sync(qh, CommitSync(self.commits));
+        }
+        let popup_frames = self.frame - self.fill_drift_start.1;
+        if self.config.fill_drift && !blank && popup_frames.is_multiple_of(FILL_DRIFT_INTERVAL) {
+            self.log_fill_drift();
+        }
+        if let (Some(every), false) = (self.config.pool_stats, blank) {
+            if self.frame % every == 0 {
+                println!("Pool at frame {}: {}", self.frame, self.pool_stats());
+                self.pool_logged_len = self.shm_pool.len();
+            }
+        }
+        if self.deactivate_at.is_some_and(|at| self.frame >= at) {
+            println!("Deactivating at frame {} (--deactivate-after)", self.frame);
+            self.simulate_deactivate(qh);
+        }
+    }
+
+    // For --batch-sizes.
+    fn log_batch(&mut self, events: usize) {
+        println!("Dispatched {events} events");
+        if let Some(usual) = self.batches.record(events) {
+            warning!("{events} events in one dispatch, usually {usual:.1}");
+        }
+    }
+
+    // For --fill-drift: compares the rows this popup has filled with what
+    // one frame per refresh would have filled in the same time. Extra
+    // redraws show up as a growing positive difference.
+    fn log_fill_drift(&self) {
+        let (start, start_frame) = self.fill_drift_start;
+        let elapsed = self.clock.now().saturating_duration_since(start);
+        let refresh = self
+            .surface_outputs
+            .iter()
+            .find_map(|output| {
+                let (_, info) = self.outputs.iter().find(|(o, _)| o == output)?;
+                (info.refresh > 0).then_some(info.refresh)
+            })
+            .unwrap_or(DEFAULT_REFRESH);
+        let rows = |frames: usize| frames / self.config.frames_per_row;
+        let actual = rows(self.frame - start_frame);
+        let expected = rows(frames_in(elapsed, refresh));
+        println!(
+            "Fill after {elapsed:?}: {actual} rows, expected {expected} at {}Hz ({:+})",
+            refresh as f64 / 1000.0,
+            actual as isize - expected as isize
+        );
+    }
+
+    // For --shared-buffer: draws again once the compositor releases our
+    // buffer. The release is an event, so checking after each dispatch
+    // doesn't spin.
+    fn redraw_on_release(&mut self, qh: &QueueHandle<App>) {
+        let qh = qh.clone();
+        self.loop_handle.insert_idle(move |app| {
+            if app.buffer.slot().has_active_buffers() {
+                return app.redraw_on_release(&qh);
+            }
+            app.awaiting_release = false;
+            if app.popup_shown() && !app.exit {
+                app.draw(&qh);
+            }
+        });
+    }
+
+    // How big the shm pool is (and how much it grew since last logged), and
+    // how much of it our buffers take up.
+    fn pool_stats(&self) -> String {
+        let buffers: Vec<&Buffer> = std::iter::once(&self.buffer)
+            .chain(self.spare_buffers.iter().map(|(buffer, _)| buffer))
+            .collect();
+        let used: usize = buffers.iter().map(|buffer| buffer.slot().len()).sum();
+        let busy = buffers
+            .iter()
+            .filter(|buffer| buffer.slot().has_active_buffers())
+            .count();
+        let capacity = self.shm_pool.len();
+        let delta = capacity as isize - self.pool_logged_len as isize;
+        pool_stats_line(capacity, delta, buffers.len(), used, busy)
+    }
+
+    // For --buffer-age-emulation: makes `buffer` one the compositor isn't
+    // using, swapping in a spare if need be, so only what changed since that
+    // buffer's last frame needs redrawing. Up to BUFFER_RING buffers take
+    // turns, like a compositor-managed swapchain.
+    fn pick_aged_buffer(&mut self) {
+        let layout = self.layout;
+        let fits = |buffer: &Buffer| {
+            buffer.height() as usize == layout.height && buffer.stride() as usize == layout.stride
+        };
+        self.spare_buffers.retain(|(buffer, _)| fits(buffer));
+        if fits(&self.buffer) && self.buffer.canvas(&mut self.shm_pool).is_some() {
+            return;
+        }
+        let pool = &mut self.shm_pool;
+        let free = self
+            .spare_buffers
+            .iter_mut()
+            .position(|(buffer, _)| buffer.canvas(pool).is_some());
+        let (buffer, frame) = match free {
+            Some(i) => self.spare_buffers.swap_remove(i),
+            None if self.spare_buffers.len() + 1 < BUFFER_RING => {
+                let buffer = create_buffer(&mut self.shm_pool, layout).0;
+                self.objects.created(buffer.wl_buffer().id());
+                (buffer, None)
+            }
+            // All busy: commit_buffer replaces the current one.
+            None => return,
+        };
+        let old = mem::replace(&mut self.buffer, buffer);
+        let old_frame = mem::replace(&mut self.buffer_frame, frame);
+        if fits(&old) {
+            self.spare_buffers.push((old, old_frame));
+        }
+    }
+
+    // Buffers no longer hold an earlier frame of what we're drawing now.
+    fn forget_buffer_frames(&mut self) {
+        self.buffer_frame = None;
+        for (_, frame) in &mut self.spare_buffers {
+            *frame = None;
+        }
+    }
+
+    // Damages (part of) the buffer, clamped to its bounds. Damage outside the
+    // buffer is our bug, and shouldn't be left for the compositor to judge.
+    fn damage_checked(&self, x: i32, y: i32, width: i32, height: i32) {
+        let requested = (x, y, width, height);
+        let clamped = clamp_damage(requested, self.layout);
+        if clamped != requested {
+            warning!(
+                "damage {width}x{height}+{x}+{y} is outside the {}x{} buffer, clamping",
+                self.layout.width,
+                self.layout.height
+            );
+        }
+        let (x, y, width, height) = clamped;
+        self.emit(
+            self.surface.id(),
+            "damage_buffer",
+            format_args!("{x}, {y}, {width}, {height}"),
+        );
+        self.surface.damage_buffer(x, y, width, height);
+    }
+}
+
+// Frame callbacks drive the animation.
+impl Dispatch<WlCallback, WlSurface> for App {
+    fn event(
+        state: &mut Self,
+        _: &WlCallback,
+        _: wl_callback::Event,
+        surface: &WlSurface,
+        _: &Connection,
+        qhandle: &QueueHandle<Self>,
+    ) {
+        let _span = state.handling("frame callback");
+        // This may be behind the extra redraws.
+        let replaced = *surface != state.surface;
+        if replaced || !state.buffer_attached {
+            warning!("frame callback for {}, which has no buffer", surface.id());
+        }
+        if replaced {
+            // Drawing now would commit to the surface that replaced it.
+            return;
+        }
+        state.frame_pending = false;
+        if let Some(latency) = take_elapsed(&mut state.popup_created_at, &*state.clock) {
+            println!(
+                "Popup #{} first frame after {latency:?}",
+                state.popups_opened
+            );
+        }
+        if let Some(wanted) = state.wanted_popup.take() {
+            if wanted != state.popup_shown() {
+                // Showing draws the next frame itself. After hiding there's
+                // nothing to draw, and no callback would come for it.
+                state.set_popup(wanted, qhandle);
+                return;
+            }
+        }
+        if state.popup_unmapped {
+            // Drawing would map it again.
+            return;
+        }
+        if state.config.sync_driven_draw && !state.popup_shown() {
+            // Syncs would carry on without a popup; showing one draws again.
+            return;
+        }
+        if let (Some(limit), true) = (state.config.probe_popup_limit, state.popup_shown()) {
+            if state.popups_opened >= limit {
+                state.shutdown();
+            } else {
+                // Always a new popup object, whatever --hide-mode says.
+                // Showing draws the next frame.
+                state.hide_popup();
+                state.destroy_popup();
+                state.popup_unmapped = false;
+                state.show_popup(qhandle);
+            }
+            return;
+        }
+        state.draw(qhandle)
+    }
+}
+
+// A wl_display.sync issued right after a commit: when it's done, the
+// compositor has processed that commit. This separates "we sent two commits"
+// from "the compositor rendered twice".
+struct CommitSync(usize);
+impl Dispatch<WlCallback, CommitSync> for App {
+    fn event(
+        state: &mut Self,
+        _: &WlCallback,
+        _: wl_callback::Event,
+        sync: &CommitSync,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let _span = state.handling("commit sync");
+        state.synced_commits = sync.0;
+        println!("Commit #{} synced (latest #{})", sync.0, state.commits);
+    }
+}
+
+// What the compositor has told us about an output.
+#[derive(Debug)]
+struct Output {
+    // The registry name, for when it's unplugged.
+    global: u32,
+    name: Option<String>,
+    position: (i32, i32),
+    // Of the current mode, in pixels.
+    size: (i32, i32),
+    // Of the current mode, in mHz.
+    refresh: i32,
+    scale: i32,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            global: 0,
+            name: None,
+            position: (0, 0),
+            size: (0, 0),
+            refresh: 0,
+            scale: 1,
+        }
+    }
+}
+
+impl Dispatch<WlOutput, ()> for App {
+    fn event(
+        state: &mut Self,
+        proxy: &WlOutput,
+        event: wl_output::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let _span = state.handling("wl_output");
+        let Some((_, output)) = state.outputs.iter_mut().find(|(o, _)| o == proxy) else {
+            return;
+        };
+        match event {
+            wl_output::Event::Geometry { x, y, .. } => output.position = (x, y),
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                refresh,
+            } if flags.contains(wl_output::Mode::Current) => {
+                output.size = (width, height);
+                output.refresh = refresh;
+            }
+            wl_output::Event::Scale { factor } => output.scale = factor,
+            wl_output::Event::Name { name } => output.name = Some(name),
+            wl_output::Event::Done => println!(
+                "Output {}: {}x{} at {},{}, scale {}",
+                output.name.as_deref().unwrap_or("(unnamed)"),
+                output.size.0,
+                output.size.1,
+                output.position.0,
+                output.position.1,
+                output.scale
+            ),
+            _ => {}
+        }
+    }
+}
+
+// The popup might only misbehave on some outputs.
+impl Dispatch<WlSurface, ()> for App {
+    fn event(
+        state: &mut Self,
+        surface: &WlSurface,
+        event: wl_surface::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let _span = state.handling("wl_surface");
+        if *surface != state.surface {
+            // Replaced by --workaround.
+            return;
+        }
+        match event {
+            wl_surface::Event::Enter { output } => {
+                println!("Popup entered output {}", state.output_name(&output));
+                state.surface_outputs.push(output);
+            }
+            wl_surface::Event::Leave { output } => {
+                println!("Popup left output {}", state.output_name(&output));
+                state.surface_outputs.retain(|o| *o != output);
+            }
+            _ => {}
+        }
+    }
+}
+
+// For --click-candidates, we need a pointer if the seat has one.
+impl Dispatch<WlSeat, ()> for App {
+    fn event(
+        state: &mut Self,
+        seat: &WlSeat,
+        event: wl_seat::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let _span = state.handling("wl_seat");
+        let wl_seat::Event::Capabilities {
+            capabilities: WEnum::Value(capabilities),
+        } = event
+        else {
+            return;
+        };
+        let has_pointer = capabilities.contains(wl_seat::Capability::Pointer);
+        if has_pointer && state.pointer.is_none() && !state.candidates.is_empty() {
+            state.pointer = Some(seat.get_pointer(qh, ()));
+        } else if !has_pointer {
+            if let Some(pointer) = state.pointer.take() {
+                if pointer.version() >= 3 {
+                    pointer.release();
+                }
+            }
+            state.pointer_y = None;
+        }
+    }
+}
+
+// Clicking on the popup commits the candidate under the pointer.
+impl Dispatch<WlPointer, ()> for App {
+    fn event(
+        state: &mut Self,
+        _: &WlPointer,
+        event: wl_pointer::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let _span = state.handling("wl_pointer");
+        match event {
+            wl_pointer::Event::Enter {
+                surface, surface_y, ..
+            } => state.pointer_y = (surface == state.surface).then_some(surface_y),
+            wl_pointer::Event::Motion { surface_y, .. } if state.pointer_y.is_some() => {
+                state.pointer_y = Some(surface_y)
+            }
+            wl_pointer::Event::Leave { .. } => state.pointer_y = None,
+            wl_pointer::Event::Button {
+                state: WEnum::Value(wl_pointer::ButtonState::Pressed),
+                ..
+            } => {
+                let Some(y) = state.pointer_y else {
+                    return;
+                };
+                let index = candidate_at(y, state.layout.height, state.candidates.len());
+                let candidate = state.candidates[index].clone();
+                println!("Clicked candidate {index}: {candidate:?}");
+                state.commit_text(&candidate);
+            }
+            _ => {}
+        }
+    }
+}
+
+// The popup is split into equal bands, one per candidate, top to bottom.
+fn candidate_at(y: f64, height: usize, count: usize) -> usize {
+    let index = (y.max(0.0) * count as f64 / height as f64) as usize;
+    index.min(count - 1)
+}
+
+// App::emit, for before there's an App.
+fn log_request(config: &Config, object: impl fmt::Display, request: &str, args: fmt::Arguments) {
+    if config.log_requests {
+        let line = format!("{object}.{request}({args})");
+        println!("-> {line}");
+        #[cfg(test)]
+        tests::LOGGED_REQUESTS.with_borrow_mut(|requests| requests.push(line));
+    }
+}
+
+fn create_surface(compositor: &WlCompositor, qh: &QueueHandle<App>, config: &Config) -> WlSurface {
+    let surface = compositor.create_surface(qh, ());
+    let Some(transform) = buffer_transform(config.transform) else {
+        return surface;
+    };
+    // Needs wl_surface v2, and we require v4.
+    log_request(
+        config,
+        surface.id(),
+        "set_buffer_transform",
+        format_args!("{transform:?}"),
+    );
+    surface.set_buffer_transform(transform);
+    surface
+}
+
+// What to pass to set_buffer_transform, if anything.
+fn buffer_transform(transform: Transform) -> Option<wl_output::Transform> {
+    Some(match transform {
+        Transform::Normal => return None,
+        Transform::Rotate90 => wl_output::Transform::_90,
+        Transform::Rotate180 => wl_output::Transform::_180,
+        Transform::Rotate270 => wl_output::Transform::_270,
+    })
+}
+
+fn pool_stats_line(
+    capacity: usize,
+    delta: isize,
+    buffers: usize,
+    used: usize,
+    busy: usize,
+) -> String {
+    format!(
+        "{capacity} bytes ({delta:+} since last logged), {buffers} buffers using {used} bytes, {busy} held by the compositor"
+    )
+}
+
+// How many frames one per refresh (in mHz) gives in `elapsed`.
+fn frames_in(elapsed: Duration, refresh: i32) -> usize {
+    (elapsed.as_secs_f64() * f64::from(refresh) / 1000.0) as usize
+}
+
+// A damage rectangle (x, y, width, height), cut down to fit in the buffer.
+fn clamp_damage(
+    (x, y, width, height): (i32, i32, i32, i32),
+    layout: Layout,
+) -> (i32, i32, i32, i32) {
+    let (max_x, max_y) = (layout.width as i32, layout.height as i32);
+    let (x0, y0) = (x.clamp(0, max_x), y.clamp(0, max_y));
+    let x1 = x.saturating_add(width).clamp(x0, max_x);
+    let y1 = y.saturating_add(height).clamp(y0, max_y);
+    (x0, y0, x1 - x0, y1 - y0)
+}
+
+fn create_buffer(shm: &mut SlotPool, layout: Layout) -> (Buffer, &mut [u8]) {
+    shm.create_buffer(
+        layout.width as i32,
+        layout.height as i32,
+        layout.stride as i32,
+        wl_shm::Format::Argb8888,
+    )
+    .expect("create buffer")
+}
+
+// Dumb framework boilerplate.
+delegate_registry!(App);
+delegate_noop!(App: ignore ZwpInputMethodManagerV2);
+delegate_noop!(App: ignore ZwpVirtualKeyboardManagerV1);
+delegate_noop!(App: ignore ZwpVirtualKeyboardV1);
+delegate_noop!(App: ignore WlCompositor);
+delegate_noop!(App: ignore WlShm);
+delegate_noop!(App: ignore WlBuffer);
+
+impl ProvidesRegistryState for App {
+    fn registry(&mut self) -> &mut RegistryState {
+        &mut self.registry_state
+    }
+
+    fn runtime_add_global(
+        &mut self,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+        _: u32,
+        _: &str,
+        _: u32,
+    ) {
+        // Outputs plugged in later aren't tracked.
+    }
+
+    fn runtime_remove_global(
+        &mut self,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+        name: u32,
+        interface: &str,
+    ) {
+        if interface == WlOutput::interface().name {
+            self.output_removed(name, qh);
+        }
+    }
+}
+
+struct Provider<T>(T);
+impl<T: Proxy, const N: u32> ProvidesBoundGlobal<T, N> for Provider<T> {
+    fn bound_global(&self) -> Result<T, smithay_client_toolkit::error::GlobalError> {
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::ManualClock;
+    use fake_compositor::FakeCompositor;
+    use key_sink::RecordingSink;
+    use std::cell::RefCell;
+    use wayland_client::backend::protocol::Argument;
+
+    thread_local! {
+        // What `warning!` printed on this thread.
+        pub(super) static WARNINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        // What --log-requests logged on this thread.
+        pub(super) static LOGGED_REQUESTS: RefCell<Vec<String>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    // The warnings since last asked.
+    fn warnings() -> Vec<String> {
+        WARNINGS.take()
+    }
+
+    // Any key will do, as they all toggle the popup.
+    const KEY: u32 = 30;
+
+    // Our App, talking to a FakeCompositor.
+    struct Harness {
+        fake: FakeCompositor,
+        conn: Connection,
+        app: App,
+        event_loop: EventLoop<'static, App>,
+    }
+
+    impl Harness {
+        fn new(config: Config) -> Self {
+            Self::with_fake(FakeCompositor::new(), config).unwrap()
+        }
+
+        // Our App set up against `fake`, or why that failed.
+        fn with_fake(
+            (mut fake, conn): (FakeCompositor, Connection),
+            config: Config,
+        ) -> Result<Self, Box<dyn Error>> {
+            let (app, event_loop) = fake.serve(|| setup(&conn, &config))?;
+            let mut harness = Harness {
+                fake,
+                conn,
+                app,
+                event_loop,
+            };
+            harness.requests();
+            Ok(harness)
+        }
+
+        // Lets the compositor handle our requests, and us its events.
+        fn roundtrip(&mut self) {
+            self.conn.flush().unwrap();
+            self.fake.dispatch();
+            self.event_loop
+                .dispatch(Duration::ZERO, &mut self.app)
+                .unwrap();
+            // And whatever we did about them.
+            self.conn.flush().unwrap();
+            self.fake.dispatch();
+        }
+
+        // The requests we've made since last asked.
+        fn requests(&mut self) -> Vec<String> {
+            self.roundtrip();
+            self.fake.take_requests()
+        }
+
+        // As when a text input gets focus.
+        fn activate(&mut self) {
+            let input_method = self.fake.newest("zwp_input_method_v2");
+            self.fake.send(&input_method, "activate", vec![]);
+            self.fake.send(&input_method, "done", vec![]);
+            self.roundtrip();
+        }
+
+        // As when the compositor shows our latest frame: it's done with the
+        // buffer, and wants the next.
+        fn present(&mut self) {
+            let buffer = self.fake.newest("wl_buffer");
+            self.fake.send(&buffer, "release", vec![]);
+            let callback = self.fake.newest("wl_callback");
+            self.fake.send(&callback, "done", vec![Argument::Uint(0)]);
+            self.roundtrip();
+        }
+
+        // Presses and releases `key` on the grabbed keyboard.
+        fn type_key(&mut self, key: u32) {
+            let grab = self.fake.newest("zwp_input_method_keyboard_grab_v2");
+            for state in [1, 0] {
+                let args = [0, 0, key, state].map(Argument::Uint).to_vec();
+                self.fake.send(&grab, "key", args);
+            }
+            self.roundtrip();
+        }
+    }
+
+    #[test]
+    fn reusing_the_surface_for_a_popup_warns() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        // Shown, then hidden.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        assert_eq!(warnings(), Vec::<String>::new());
+        harness.type_key(KEY);
+        let surface = harness.app.surface.id();
+        assert_eq!(
+            warnings(),
+            [format!(
+                "reusing {surface} for a new popup, but it already has the popup role"
+            )]
+        );
+    }
+
+    #[test]
+    fn reconnecting_counts_reconnects_and_gives_up() {
+        let link = Link::Connected { reconnects: 0 }.disconnected();
+        assert_eq!(
+            link,
+            Link::Disconnected {
+                reconnects: 0,
+                failed: 0
+            }
+        );
+        let link = link.attempted(false).attempted(true);
+        assert_eq!(link, Link::Connected { reconnects: 1 });
+        let mut link = link.disconnected();
+        for _ in 1..RECONNECT_ATTEMPTS {
+            link = link.attempted(false);
+        }
+        assert_ne!(link, Link::GaveUp);
+        assert_eq!(link.attempted(false), Link::GaveUp);
+    }
+
+    #[test]
+    fn disconnects_are_told_from_protocol_errors() {
+        let io = |kind: ErrorKind| calloop::Error::IoError(kind.into());
+        assert!(is_disconnect(&io(ErrorKind::BrokenPipe)));
+        assert!(is_disconnect(&io(ErrorKind::ConnectionReset)));
+        // What dispatch returns after a protocol error: EPROTO.
+        let eproto = calloop::Error::IoError(std::io::Error::from_raw_os_error(71));
+        assert!(!is_disconnect(&eproto));
+        let lost = GlobalError::Backend(WaylandError::Io(ErrorKind::BrokenPipe.into()));
+        assert!(is_disconnect(&lost));
+    }
+
+    #[test]
+    fn commit_syncs_are_matched_to_their_commits() {
+        let mut harness = Harness::new(Config {
+            sync_commits: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        assert_eq!(harness.app.synced_commits, 0);
+        // The compositor answers once it has handled the commit.
+        harness.roundtrip();
+        assert_eq!(harness.app.synced_commits, 1);
+        // Hidden, then shown again.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        harness.roundtrip();
+        assert_eq!((harness.app.commits, harness.app.synced_commits), (2, 2));
+    }
+
+    #[test]
+    fn exit_key_tears_down_in_protocol_order() {
+        let mut harness = Harness::new(Config {
+            exit_key: Some("esc".into()),
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        harness.requests();
+        harness.type_key(1);
+        let teardown: Vec<String> = harness
+            .requests()
+            .into_iter()
+            .filter(|r| r.ends_with(".release()") || r.ends_with(".destroy()"))
+            .map(|r| r[..r.find('@').unwrap()].to_owned())
+            .collect();
+        assert_eq!(
+            teardown,
+            [
+                "zwp_input_method_keyboard_grab_v2",
+                "zwp_input_popup_surface_v2",
+                "zwp_input_method_v2"
+            ]
+        );
+        assert!(harness.app.exit);
+    }
+
+    #[test]
+    fn commit_serials_never_go_backwards() {
+        assert_eq!(check_serial(2, 3), Ok(()));
+        assert_eq!(check_serial(3, 3), Ok(()));
+        assert_eq!(
+            check_serial(3, 2),
+            Err("commit serial went from 3 to 2".to_owned())
+        );
+    }
+
+    #[test]
+    fn each_done_is_committed_with_its_serial() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        let commit = format!("{}.commit(1)", harness.app.input_method.id());
+        assert!(harness.requests().contains(&commit));
+    }
+
+    #[test]
+    fn heartbeat_follows_last_activity() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(5);
+        assert_eq!(next_heartbeat(start, interval), start + interval);
+        let later = start + Duration::from_secs(3);
+        assert_eq!(next_heartbeat(later, interval), later + interval);
+    }
+
+    #[test]
+    fn clear_on_open_commits_a_blank_frame_first() {
+        let mut harness = Harness::new(Config {
+            clear_on_open: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        assert_eq!(harness.app.commits, 1);
+        // The blank frame isn't part of the animation.
+        assert_eq!(harness.app.frame, 0);
+    }
+
+    #[test]
+    fn log_wire_captures_our_requests() {
+        // Redirecting stderr would take over the test runner's, so that
+        // happens in a child running just this test.
+        const LOG: &str = "LOG_WIRE_TEST_FILE";
+        if let Some(path) = env::var_os(LOG) {
+            log_wire(Path::new(&path)).unwrap();
+            Harness::new(Config::default());
+            return;
+        }
+        let path = env::temp_dir().join(format!("log-wire-{}", std::process::id()));
+        let child = std::process::Command::new(env::current_exe().unwrap())
+            .args(["--exact", "tests::log_wire_captures_our_requests"])
+            .arg("--nocapture")
+            .env(LOG, &path)
+            .output()
+            .unwrap();
+        assert!(child.status.success(), "{child:?}");
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(log.contains("-> zwp_input_method_manager_v2@"), "{log}");
+    }
+
+    #[test]
+    fn workaround_gives_each_popup_a_new_surface_and_buffer() {
+        let mut harness = Harness::new(Config {
+            workaround: true,
+            ..Default::default()
+        });
+        harness.activate();
+        let mut surfaces = Vec::new();
+        for _ in 0..2 {
+            harness.type_key(KEY);
+            let requests = harness.requests();
+            let created = |what: &str| requests.iter().filter(|r| r.contains(what)).count();
+            assert_eq!(created(".create_surface("), 1, "{requests:?}");
+            assert_eq!(created(".create_buffer("), 1, "{requests:?}");
+            surfaces.push(harness.app.surface.id());
+            harness.type_key(KEY);
+        }
+        assert_ne!(surfaces[0], surfaces[1]);
+        assert_eq!(warnings(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn pending_state_waits_for_done() {
+        let mut harness = Harness::new(Config::default());
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.send(&input_method, "activate", vec![]);
+        let text = Argument::Str(Some(Box::new(c"hello".into())));
+        let args = vec![text, Argument::Uint(5), Argument::Uint(5)];
+        harness.fake.send(&input_method, "surrounding_text", args);
+        assert_eq!(harness.requests(), Vec::<String>::new());
+        assert!(!harness.app.current.active);
+        assert_eq!(harness.app.current.surrounding_text, None);
+
+        harness.fake.send(&input_method, "done", vec![]);
+        let requests = harness.requests();
+        assert!(requests[0].contains(".grab_keyboard("), "{requests:?}");
+        assert!(harness.app.current.active);
+        let surrounding = Some(SurroundingText::new("hello".into(), 5, 5));
+        assert_eq!(harness.app.current.surrounding_text, surrounding);
+    }
+
+    #[test]
+    fn no_vk_skips_the_virtual_keyboard() {
+        let mut harness = Harness::new(Config {
+            no_vk: true,
+            ..Default::default()
+        });
+        assert!(harness.app.virtual_keyboard.is_none());
+        harness.activate();
+        harness.type_key(KEY);
+        let requests = harness.requests();
+        assert!(!requests.iter().any(|r| r.contains("virtual_keyboard")));
+        // The key still toggled the popup.
+        assert!(harness.app.open_popup.is_some());
+    }
+
+    #[test]
+    fn max_runtime_expiry() {
+        let now = Instant::now();
+        assert!(!expired(None, now));
+        assert!(!expired(Some(now + Duration::from_secs(1)), now));
+        assert!(expired(Some(now), now));
+        assert!(expired(Some(now - Duration::from_secs(1)), now));
+    }
+
+    #[test]
+    fn fit_rectangle_resizes_the_buffer() {
+        let mut harness = Harness::new(Config {
+            fit_rectangle: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        let popup = harness.fake.newest("zwp_input_popup_surface_v2");
+        let rectangle = [0, 0, 20, 40].map(Argument::Int).to_vec();
+        harness.fake.send(&popup, "text_input_rectangle", rectangle);
+        harness.roundtrip();
+        assert_eq!(harness.app.popup_rectangle, Some((0, 0, 20, 40)));
+        // The next popup is drawn at the new size.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        assert_eq!(harness.app.buffer.height(), 40);
+        assert_eq!(harness.app.buffer.stride(), 20 * 4);
+        let requests = harness.requests();
+        assert!(requests
+            .iter()
+            .any(|r| r.contains(".damage_buffer(0, 0, 20, 40)")));
+    }
+
+    #[test]
+    fn protocol_errors_are_reported_with_the_last_popup_action() {
+        let err = ProtocolError {
+            code: 0,
+            object_id: 3,
+            object_interface: "wl_surface".into(),
+            message: "role already set".into(),
+        };
+        assert_eq!(protocol_error_report(None, Some("showing popup")), None);
+        assert_eq!(
+            protocol_error_report(Some(&err), Some("showing popup")).unwrap(),
+            "Protocol error after showing popup: wl_surface@3 code 0: role already set"
+        );
+        assert_eq!(
+            protocol_error_report(Some(&err), None).unwrap(),
+            "Protocol error: wl_surface@3 code 0: role already set"
+        );
+    }
+
+    #[test]
+    fn debounce_rejects_toggles_within_delay() {
+        let clock = ManualClock::new();
+        let delay = Duration::from_millis(100);
+        let mut last = None;
+        assert!(debounce(&mut last, &clock, delay));
+        clock.advance(Duration::from_millis(99));
+        assert!(!debounce(&mut last, &clock, delay));
+        clock.advance(Duration::from_millis(1));
+        assert!(debounce(&mut last, &clock, delay));
+        // The rejected toggle didn't restart the delay, the accepted one did.
+        clock.advance(Duration::from_millis(50));
+        assert!(!debounce(&mut last, &clock, delay));
+    }
+
+    #[test]
+    fn toggle_delay_follows_the_apps_clock() {
+        let mut harness = Harness::new(Config {
+            toggle_delay: Duration::from_secs(1),
+            ..Default::default()
+        });
+        let clock = Rc::new(ManualClock::new());
+        harness.app.clock = clock.clone();
+        harness.activate();
+        harness.type_key(KEY);
+        assert!(harness.app.open_popup.is_some());
+        harness.type_key(KEY);
+        assert!(harness.app.open_popup.is_some());
+        clock.advance(Duration::from_secs(1));
+        harness.type_key(KEY);
+        assert!(harness.app.open_popup.is_none());
+    }
+
+    #[test]
+    fn keymap_is_loaded_nul_terminated() {
+        use std::io::{Read, Seek};
+        let path = env::temp_dir().join(format!("sway-im-popup-keymap-{}", std::process::id()));
+        fs::write(&path, "xkb_keymap {};").unwrap();
+        let (mut keymap, size) = load_keymap(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(size, 15);
+        let mut text = String::new();
+        keymap.rewind().unwrap();
+        keymap.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "xkb_keymap {};\0");
+    }
+
+    #[test]
+    fn deactivating_releases_the_grab_and_keeps_the_popup() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.type_key(KEY);
+        harness.requests();
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.send(&input_method, "deactivate", vec![]);
+        harness.fake.send(&input_method, "done", vec![]);
+        let requests = harness.requests();
+        assert!(requests[0].ends_with(".release()"), "{requests:?}");
+        // The popup is only destroyed once the grab is gone, if at all.
+        assert!(!requests
+            .iter()
+            .any(|r| r.contains("zwp_input_popup_surface_v2")));
+        assert!(harness.app.status().contains("grab_held=false"));
+        assert_eq!(warnings(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unmapped_popups_time_out() {
+        let timeout = Duration::from_millis(5);
+        let mut harness = Harness::new(Config {
+            map_timeout: Some(timeout),
+            ..Default::default()
+        });
+        harness.activate();
+        // The first popup hears from the compositor in time.
+        harness.type_key(KEY);
+        let popup = harness.fake.newest("zwp_input_popup_surface_v2");
+        let rectangle = [0, 0, 1, 1].map(Argument::Int).to_vec();
+        harness.fake.send(&popup, "text_input_rectangle", rectangle);
+        harness.roundtrip();
+        thread::sleep(timeout);
+        harness.roundtrip();
+        assert_eq!(warnings(), Vec::<String>::new());
+        // The second one doesn't.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        thread::sleep(timeout);
+        harness.roundtrip();
+        let warnings = warnings();
+        assert_eq!(warnings.last().unwrap(), "popup #2 not mapped after 5ms");
+    }
+
+    #[test]
+    fn identical_keymaps_are_forwarded_once() {
+        use std::os::fd::AsRawFd;
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        let grab = harness.fake.newest("zwp_input_method_keyboard_grab_v2");
+        let path = env::temp_dir().join(format!("sway-im-popup-keymaps-{}", std::process::id()));
+        let mut send_keymap = |text: &str| {
+            fs::write(&path, text).unwrap();
+            let (keymap, size) = load_keymap(&path).unwrap();
+            // Twice, as regrabs do.
+            for _ in 0..2 {
+                let args = vec![
+                    Argument::Uint(1),
+                    Argument::Fd(keymap.as_raw_fd()),
+                    Argument::Uint(size),
+                ];
+                harness.fake.send(&grab, "keymap", args);
+            }
+            harness.requests()
+        };
+        let keymaps =
+            |requests: Vec<String>| requests.iter().filter(|r| r.contains(".keymap(")).count();
+        assert_eq!(keymaps(send_keymap("xkb_keymap {};")), 1);
+        assert_eq!(keymaps(send_keymap("xkb_keymap { };")), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_virtual_keyboard_manager_is_optional() {
+        let missing = || FakeCompositor::without("zwp_virtual_keyboard_manager_v1");
+        let harness = Harness::with_fake(missing(), Config::default()).unwrap();
+        assert!(harness.app.virtual_keyboard.is_none());
+        let warnings = warnings();
+        assert!(
+            warnings[0].starts_with("no virtual keyboard"),
+            "{warnings:?}"
+        );
+
+        let config = Config {
+            require_vk: true,
+            ..Default::default()
+        };
+        assert!(Harness::with_fake(missing(), config).is_err());
+    }
+
+    #[test]
+    fn frame_callbacks_for_replaced_surfaces_warn_and_draw_nothing() {
+        let mut harness = Harness::new(Config {
+            workaround: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        let old_surface = harness.app.surface.id();
+        let callback = harness.fake.newest("wl_callback");
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        let commits = harness.app.commits;
+        harness
+            .fake
+            .send(&callback, "done", vec![Argument::Uint(0)]);
+        harness.roundtrip();
+        assert_eq!(
+            warnings(),
+            [format!(
+                "frame callback for {old_surface}, which has no buffer"
+            )]
+        );
+        assert_eq!(harness.app.commits, commits);
+    }
+
+    #[test]
+    fn fresh_buffer_mode_allocates_each_frame() {
+        let buffers_created = |fresh_buffer_each_frame| {
+            let mut harness = Harness::new(Config {
+                fresh_buffer_each_frame,
+                ..Default::default()
+            });
+            harness.activate();
+            harness.type_key(KEY);
+            for _ in 0..3 {
+                harness.present();
+            }
+            let requests = harness.requests();
+            requests
+                .iter()
+                .filter(|r| r.contains(".create_buffer("))
+                .count()
+        };
+        // Four frames. Without reuse each gets its own buffer, with reuse they
+        // share the one made at startup.
+        assert_eq!(buffers_created(true), 4);
+        assert_eq!(buffers_created(false), 0);
+    }
+
+    #[test]
+    fn exit_waits_for_the_buffer_to_be_released() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.type_key(KEY);
+        assert!(harness.app.buffer.slot().has_active_buffers());
+        // Held until the timeout.
+        let Harness {
+            app, event_loop, ..
+        } = &mut harness;
+        let start = Instant::now();
+        release_buffers(app, event_loop, Duration::from_millis(50)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            warnings().last().unwrap(),
+            "compositor still holds our buffer, exiting anyway"
+        );
+        assert!(harness
+            .requests()
+            .contains(&format!("{}.attach(nil, 0, 0)", harness.app.surface.id())));
+        // Released while we wait.
+        let count = warnings().len();
+        let buffer = harness.fake.newest("wl_buffer");
+        harness.fake.send(&buffer, "release", vec![]);
+        let Harness {
+            app, event_loop, ..
+        } = &mut harness;
+        release_buffers(app, event_loop, Duration::from_secs(10)).unwrap();
+        assert!(!harness.app.buffer.slot().has_active_buffers());
+        assert_eq!(warnings().len(), count);
+    }
+
+    #[test]
+    fn color_cycle_gives_each_popup_the_next_color() {
+        let mut harness = Harness::new(Config {
+            color_cycle: true,
+            ..Default::default()
+        });
+        harness.activate();
+        let mut colors = Vec::new();
+        for _ in 0..3 {
+            harness.type_key(KEY);
+            colors.push(harness.app.popup_color);
+            harness.type_key(KEY);
+        }
+        assert_eq!(colors, draw::PALETTE[..3]);
+        let palette = &draw::PALETTE;
+        assert_eq!(popup_color(&Config::default(), palette, 2), draw::FILL);
+        let wrapped = popup_color(&harness.app.config, palette, palette.len());
+        assert_eq!(wrapped, draw::PALETTE[0]);
+    }
+
+    #[test]
+    fn palette_files_replace_the_cycle() {
+        let config = Config {
+            palette: Some("colors".into()),
+            ..Default::default()
+        };
+        let palette = [[1; 4], [2; 4]];
+        assert_eq!(popup_color(&config, &palette, 0), [1; 4]);
+        assert_eq!(popup_color(&config, &palette, 3), [2; 4]);
+        assert_eq!(popup_color(&config, &[], 3), draw::FILL);
+    }
+
+    #[test]
+    fn versions_are_the_newest_both_sides_support() {
+        assert_eq!(pick_version(9, 1..=9), Some(9));
+        assert_eq!(pick_version(7, 1..=9), Some(7));
+        assert_eq!(pick_version(12, 4..=6), Some(6));
+        assert_eq!(pick_version(3, 4..=6), None);
+        assert_eq!(pick_version(1, 1..=1), Some(1));
+        // The fake compositor has sway's versions.
+        let harness = Harness::new(Config::default());
+        let versions = &harness.app.versions;
+        assert!(versions.contains(&("wl_seat", 9)), "{versions:?}");
+        assert!(versions.contains(&("wl_compositor", 6)), "{versions:?}");
+    }
+
+    #[test]
+    fn recommit_makes_bare_commits_after_opening() {
+        let mut harness = Harness::new(Config {
+            recommit: 3,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.requests();
+        harness.type_key(KEY);
+        let surface = harness.app.surface.id().to_string();
+        let requests: Vec<String> = harness
+            .requests()
+            .into_iter()
+            .filter(|r| r.starts_with(&surface))
+            .collect();
+        let commit = format!("{surface}.commit()");
+        let last_attach = requests.iter().rposition(|r| r.contains(".attach("));
+        let after = &requests[last_attach.unwrap() + 1..];
+        // The drawn frame's damage, frame request and commit, then ours.
+        assert_eq!(
+            after.iter().filter(|r| **r == commit).count(),
+            4,
+            "{after:?}"
+        );
+        assert!(
+            after[after.len() - 3..].iter().all(|r| *r == commit),
+            "{after:?}"
+        );
+        assert_eq!(harness.app.commits, 4);
+    }
+
+    #[test]
+    fn toggles_log_the_resulting_popup_state() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.type_key(KEY);
+        assert!(harness.app.open_popup.is_some());
+        assert_eq!(
+            harness.app.toggle_result(),
+            "key 1: popup -> shown (1 live)"
+        );
+        harness.type_key(KEY);
+        assert!(harness.app.open_popup.is_none());
+        assert_eq!(
+            harness.app.toggle_result(),
+            "key 2: popup -> hidden (0 live)"
+        );
+    }
+
+    #[test]
+    fn no_frame_callback_draws_once() {
+        let mut harness = Harness::new(Config {
+            no_frame_callback: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        let requests = harness.requests();
+        assert!(
+            requests.iter().any(|r| r.contains(".attach(")),
+            "{requests:?}"
+        );
+        assert!(
+            !requests.iter().any(|r| r.contains(".frame(")),
+            "{requests:?}"
+        );
+        assert_eq!(harness.app.commits, 1);
+    }
+
+    #[test]
+    fn surface_enter_and_leave_track_outputs() {
+        let mut harness = Harness::new(Config::default());
+        let output = harness.fake.newest("wl_output");
+        let name = Argument::Str(Some(Box::new(c"HDMI-A-1".into())));
+        harness.fake.send(&output, "name", vec![name]);
+        harness.fake.send(&output, "done", vec![]);
+        harness.activate();
+        harness.type_key(KEY);
+        let surface = harness.fake.newest("wl_surface");
+        let args = vec![Argument::Object(output)];
+        harness.fake.send(&surface, "enter", args.clone());
+        harness.roundtrip();
+        assert_eq!(harness.app.surface_outputs.len(), 1);
+        assert!(harness.app.status().ends_with(r#"outputs=["HDMI-A-1"]"#));
+        harness.fake.send(&surface, "leave", args);
+        harness.roundtrip();
+        assert!(harness.app.surface_outputs.is_empty());
+    }
+
+    #[test]
+    fn each_press_commits_the_string() {
+        let mut harness = Harness::new(Config {
+            commit_string_on_toggle: Some("x".into()),
+            ..Default::default()
+        });
+        harness.activate();
+        harness.requests();
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        let input_method = harness.app.input_method.id();
+        let commits: Vec<String> = harness
+            .requests()
+            .into_iter()
+            .filter(|r| r.starts_with(&input_method.to_string()))
+            .filter(|r| r.contains(".commit"))
+            .collect();
+        let one = [
+            format!(r#"{input_method}.commit_string("x")"#),
+            format!("{input_method}.commit(1)"),
+        ];
+        assert_eq!(commits, [one.clone(), one].concat());
+    }
+
+    #[test]
+    fn unavailable_input_method_shuts_down() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.requests();
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.send(&input_method, "unavailable", vec![]);
+        harness.roundtrip();
+        assert!(harness.app.exit);
+        let destroy = format!("{}.destroy()", harness.app.input_method.id());
+        assert!(harness.requests().contains(&destroy));
+    }
+
+    #[test]
+    fn dispatch_errors_shut_down() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.post_error(&input_method, 0, "gone");
+        let Harness {
+            app, event_loop, ..
+        } = &mut harness;
+        let err = dispatch_until_exit(app, event_loop).unwrap_err();
+        // Not worth reconnecting after.
+        assert!(!is_disconnect(&err));
+        assert!(harness.app.exit);
+    }
+
+    #[test]
+    fn toggles_within_a_frame_collapse_to_their_net_effect() {
+        let mut harness = Harness::new(Config {
+            toggle_per_frame: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        assert!(harness.app.frame_pending);
+        harness.requests();
+        for _ in 0..3 {
+            harness.type_key(KEY);
+        }
+        // Nothing happens until the frame callback.
+        assert!(harness.app.open_popup.is_some());
+        assert_eq!(harness.app.wanted_popup, Some(false));
+        let popup_requests = |requests: Vec<String>| {
+            requests
+                .into_iter()
+                .filter(|r| r.contains("popup_surface"))
+                .count()
+        };
+        assert_eq!(popup_requests(harness.requests()), 0);
+        harness.present();
+        assert!(harness.app.open_popup.is_none());
+        assert_eq!(popup_requests(harness.requests()), 1);
+        // Shown at once, as no frame is pending. Then two toggles cancel out,
+        // leaving the popup alone.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        harness.present();
+        assert!(harness.app.open_popup.is_some());
+        assert_eq!(harness.app.popups_opened, 2);
+    }
+
+    #[test]
+    fn deactivates_the_given_number_of_frames_after_the_second_popup() {
+        let mut harness = Harness::new(Config {
+            deactivate_after: Some(3),
+            ..Config::default()
+        });
+        harness.activate();
+        // Shown, hidden, then shown again: the second popup.
+        for _ in 0..3 {
+            harness.type_key(KEY);
+        }
+        assert_eq!(harness.app.popups_opened, 2);
+        let at = harness.app.frame + 3;
+        assert_eq!(harness.app.deactivate_at, Some(at));
+        while harness.app.frame + 1 < at {
+            harness.present();
+            assert!(harness.app.grab_held());
+            assert!(harness.app.open_popup.is_some());
+        }
+        harness.present();
+        assert_eq!(harness.app.frame, at);
+        assert!(!harness.app.grab_held());
+        assert!(harness.app.open_popup.is_none());
+        assert_eq!(harness.app.deactivate_at, None);
+    }
+
+    #[test]
+    fn batched_commits_wait_for_done() {
+        let commits = |commit_mode| {
+            let mut harness = Harness::new(Config {
+                commit_string_on_toggle: Some("x".into()),
+                commit_mode,
+                ..Default::default()
+            });
+            harness.activate();
+            harness.requests();
+            for _ in 0..3 {
+                harness.type_key(KEY);
+            }
+            let input_method = harness.fake.newest("zwp_input_method_v2");
+            harness.fake.send(&input_method, "done", vec![]);
+            let commit = format!("{}.commit(", harness.app.input_method.id());
+            harness
+                .requests()
+                .iter()
+                .filter(|r| r.starts_with(&commit))
+                .count()
+        };
+        // One per key, and none for a Done that changes nothing.
+        assert_eq!(commits(CommitMode::PerKey), 3);
+        assert_eq!(commits(CommitMode::Batched), 1);
+    }
+
+    #[test]
+    fn transform_flag_maps_to_buffer_transform() {
+        assert_eq!(buffer_transform(Transform::Normal), None);
+        assert_eq!(
+            buffer_transform("90".parse().unwrap()),
+            Some(wl_output::Transform::_90)
+        );
+        assert_eq!(
+            buffer_transform("180".parse().unwrap()),
+            Some(wl_output::Transform::_180)
+        );
+        assert_eq!(
+            buffer_transform("270".parse().unwrap()),
+            Some(wl_output::Transform::_270)
+        );
+    }
+
+    #[test]
+    fn only_the_first_popup_is_drawn_late() {
+        let delay = Duration::from_millis(5);
+        let mut harness = Harness::new(Config {
+            delay_first_draw: Some(delay),
+            ..Default::default()
+        });
+        harness.activate();
+        harness.requests();
+        let attaches = |requests: Vec<String>| {
+            requests
+                .iter()
+                .filter(|r| r.contains(".attach(wl_buffer"))
+                .count()
+        };
+        harness.type_key(KEY);
+        assert_eq!(attaches(harness.requests()), 0);
+        // Shown again within the delay, the second popup draws at once, and
+        // the first popup's timer leaves it alone.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        assert_eq!(attaches(harness.requests()), 1);
+        thread::sleep(delay);
+        assert_eq!(attaches(harness.requests()), 0);
+        assert_eq!(harness.app.popups_opened, 2);
+    }
+
+    #[test]
+    fn grabbed_keys_reach_the_key_sink() {
+        let mut harness = Harness::new(Config::default());
+        let sink = RecordingSink::default();
+        harness.app.virtual_keyboard = Some(Box::new(sink.clone()));
+        harness.activate();
+        let grab = harness.fake.newest("zwp_input_method_keyboard_grab_v2");
+        let modifiers = [0, 1, 0, 0, 0].map(Argument::Uint).to_vec();
+        harness.fake.send(&grab, "modifiers", modifiers);
+        harness.type_key(KEY);
+        assert_eq!(
+            *sink.0.borrow(),
+            [
+                "modifiers 1 0 0 0".to_owned(),
+                format!("key {KEY} 1"),
+                format!("key {KEY} 0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn vk_to_log_needs_no_virtual_keyboard() {
+        let mut harness = Harness::new(Config {
+            vk_to_log: true,
+            ..Default::default()
+        });
+        assert!(harness.app.virtual_keyboard.is_some());
+        harness.activate();
+        harness.type_key(KEY);
+        let requests = harness.requests();
+        assert!(!requests.iter().any(|r| r.contains("virtual_keyboard")));
+    }
+
+    #[test]
+    fn old_protocols_are_refused() {
+        let config = |min_protocol: &str| Config {
+            min_protocol: min_protocol.parse().unwrap(),
+            ..Default::default()
+        };
+        assert!(Harness::with_fake(FakeCompositor::new(), config("seat=5")).is_ok());
+        let Err(err) = Harness::with_fake(FakeCompositor::new(), config("seat=99")) else {
+            panic!("wl_seat v99 isn't advertised");
+        };
+        assert!(matches!(
+            AppError::from(err),
+            AppError::ProtocolTooOld(message) if message.contains("wl_seat")
+        ));
+    }
+
+    #[test]
+    fn sticky_grab_survives_deactivate() {
+        let mut harness = Harness::new(Config {
+            sticky_grab: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        harness.requests();
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.send(&input_method, "deactivate", vec![]);
+        harness.fake.send(&input_method, "done", vec![]);
+        let requests = harness.requests();
+        assert!(!requests.iter().any(|r| r.ends_with(".release()")));
+        assert!(harness.app.grab_held());
+        assert_eq!(
+            warnings(),
+            ["--sticky-grab: keeping the grab while deactivated, against the protocol"]
+        );
+        // Keys still arrive, and hiding the popup doesn't warn.
+        harness.type_key(KEY);
+        assert!(harness.app.open_popup.is_none());
+        assert_eq!(warnings(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn waiting_for_a_slot_asks_again_after_unavailable() {
+        let mut harness = Harness::new(Config {
+            wait_for_slot: true,
+            ..Default::default()
+        });
+        harness.app.slot_retry_interval = Duration::from_millis(5);
+        let old = harness.app.input_method.id();
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.send(&input_method, "unavailable", vec![]);
+        assert!(harness.requests().is_empty());
+        assert!(!harness.app.exit);
+        thread::sleep(harness.app.slot_retry_interval);
+        let requests = harness.requests();
+        let new = harness.app.input_method.id();
+        assert_ne!(new, old);
+        assert_eq!(
+            requests,
+            [
+                format!("{old}.destroy()"),
+                format!(
+                    "zwp_input_method_manager_v2@{}.get_input_method(wl_seat@{}, {new})",
+                    harness.app.im_mgr.id().protocol_id(),
+                    harness.app.seat.id().protocol_id()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_first_commit_has_the_initial_color() {
+        let red: draw::Color = "ff0000".parse().unwrap();
+        let mut harness = Harness::new(Config {
+            initial_color: Some(red),
+            dump_last_buffer: Some("unused".into()),
+            ..Default::default()
+        });
+        harness.activate();
+        let all_red = |app: &App| {
+            let (_, data) = app.last_buffer.as_ref().unwrap();
+            data.chunks_exact(4).all(|pixel| pixel == red.0)
+        };
+        harness.type_key(KEY);
+        assert!(all_red(&harness.app));
+        assert_eq!(harness.app.frame, 0);
+        harness.present();
+        assert!(!all_red(&harness.app));
+        assert_eq!(harness.app.frame, 1);
+        // Only the first popup.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        assert!(!all_red(&harness.app));
+    }
+
+    #[test]
+    fn unmapping_keeps_the_popup() {
+        let mut harness = Harness::new(Config {
+            hide_mode: HideMode::Unmap,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        let popup = harness.app.open_popup.as_ref().unwrap().0.id();
+        harness.requests();
+        harness.type_key(KEY);
+        let surface = harness.app.surface.id();
+        let requests = harness.requests();
+        assert_eq!(
+            requests[..2],
+            [
+                format!("{surface}.attach(nil, 0, 0)"),
+                format!("{surface}.commit()")
+            ]
+        );
+        assert!(!requests.iter().any(|r| r.contains("popup_surface")));
+        assert!(!harness.app.popup_shown());
+        // Shown again, it's the same popup, with a buffer attached.
+        harness.type_key(KEY);
+        assert_eq!(harness.app.open_popup.as_ref().unwrap().0.id(), popup);
+        assert!(harness.app.popup_shown());
+        assert_eq!(harness.app.popups_opened, 1);
+        let requests = harness.requests();
+        assert!(requests.iter().any(|r| r.contains(".attach(wl_buffer")));
+        assert!(!requests.iter().any(|r| r.contains("popup_surface")));
+    }
+
+    #[test]
+    fn damage_is_clamped_to_the_buffer() {
+        let layout = Layout::new(100, 50, None);
+        assert_eq!(clamp_damage((0, 0, 100, 50), layout), (0, 0, 100, 50));
+        assert_eq!(clamp_damage((10, 10, 200, 200), layout), (10, 10, 90, 40));
+        assert_eq!(clamp_damage((-5, -5, 10, 10), layout), (0, 0, 5, 5));
+        assert_eq!(
+            clamp_damage((0, 0, i32::MAX, i32::MAX), layout),
+            (0, 0, 100, 50)
+        );
+        assert_eq!(clamp_damage((200, 0, 10, 10), layout), (100, 0, 0, 10));
+    }
+
+    #[test]
+    fn unplugging_the_popups_output_recreates_the_popup() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.type_key(KEY);
+        let surface = harness.app.surface.id();
+        let popup = harness.app.open_popup.as_ref().unwrap().0.id();
+        let output = harness.fake.newest("wl_output");
+        let entered = harness.fake.newest("wl_surface");
+        harness
+            .fake
+            .send(&entered, "enter", vec![Argument::Object(output)]);
+        harness.requests();
+        harness.fake.remove_global("wl_output");
+        let requests = harness.requests();
+        assert!(harness.app.outputs.is_empty());
+        assert!(harness.app.popup_shown());
+        assert_ne!(harness.app.surface.id(), surface);
+        assert!(
+            requests.contains(&format!("{popup}.destroy()")),
+            "{requests:?}"
+        );
+        assert!(
+            requests.contains(&format!("{surface}.destroy()")),
+            "{requests:?}"
+        );
+        assert!(requests
+            .iter()
+            .any(|r| r.contains(".get_input_popup_surface(")));
+    }
+
+    #[test]
+    fn unplugging_another_output_leaves_the_popup() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.type_key(KEY);
+        harness.requests();
+        harness.fake.remove_global("wl_output");
+        let requests = harness.requests();
+        assert!(harness.app.outputs.is_empty());
+        assert!(
+            !requests.iter().any(|r| r.contains("popup_surface")),
+            "{requests:?}"
+        );
+    }
+
+    #[test]
+    fn probing_stops_at_the_popup_limit() {
+        let mut harness = Harness::new(Config {
+            probe_popup_limit: Some(3),
+            ..Default::default()
+        });
+        harness.activate();
+        harness.type_key(KEY);
+        let mut popups = vec![harness.app.open_popup.as_ref().unwrap().0.id()];
+        while !harness.app.exit {
+            assert!(popups.len() <= 3, "{popups:?}");
+            harness.present();
+            if let Some(popup) = &harness.app.open_popup {
+                popups.push(popup.0.id());
+            }
+        }
+        // A new popup each frame, until the limit.
+        assert_eq!(popups.len(), 3);
+        popups.dedup();
+        assert_eq!(popups.len(), 3);
+        assert_eq!(harness.app.popups_opened, 3);
+    }
+
+    #[test]
+    fn empty_first_commits_are_background() {
+        let mut harness = Harness::new(Config {
+            clear_on_open: true,
+            dump_last_buffer: Some("unused".into()),
+            ..Default::default()
+        });
+        harness.activate();
+        let background = |app: &App| {
+            let (_, data) = app.last_buffer.as_ref().unwrap();
+            data.chunks_exact(4).all(|pixel| pixel == draw::BACKGROUND)
+        };
+        // Each popup's first commit is blank, then the animation starts.
+        for _ in 0..2 {
+            harness.type_key(KEY);
+            assert!(harness
+                .requests()
+                .iter()
+                .any(|r| r.contains(".attach(wl_buffer")));
+            assert!(background(&harness.app));
+            let frame = harness.app.frame;
+            harness.present();
+            assert_eq!(harness.app.frame, frame + 1);
+            harness.type_key(KEY);
+        }
+    }
+
+    #[test]
+    fn keys_are_routed_by_role() {
+        let (exit, cancel, toggle, other) = (1, 14, 57, KEY);
+        let role = |key, toggle_key| key_role(key, Some(exit), Some(cancel), toggle_key);
+        assert_eq!(role(exit, Some(toggle)), KeyRole::Exit);
+        assert_eq!(role(cancel, Some(toggle)), KeyRole::Cancel);
+        assert_eq!(
+            role(toggle, Some(toggle)),
+            KeyRole::Toggle { forward: false }
+        );
+        assert_eq!(role(other, Some(toggle)), KeyRole::Forward);
+        assert_eq!(role(other, None), KeyRole::Toggle { forward: true });
+        // The exit key wins, even if it's also the toggle key.
+        assert_eq!(role(exit, Some(exit)), KeyRole::Exit);
+    }
+
+    #[test]
+    fn only_the_toggle_key_toggles_and_it_isnt_forwarded() {
+        let mut harness = Harness::new(Config {
+            toggle_key: Some("space".into()),
+            cancel_key: Some("esc".into()),
+            ..Default::default()
+        });
+        let sink = RecordingSink::default();
+        harness.app.virtual_keyboard = Some(Box::new(sink.clone()));
+        harness.activate();
+        harness.type_key(KEY);
+        assert!(!harness.app.popup_shown());
+        harness.type_key(57);
+        assert!(harness.app.popup_shown());
+        harness.type_key(1);
+        assert!(!harness.app.popup_shown());
+        assert_eq!(
+            *sink.0.borrow(),
+            [format!("key {KEY} 1"), format!("key {KEY} 0")]
+        );
+    }
+
+    #[test]
+    fn spawn_on_activate_shows_the_popup_without_a_key() {
+        let mut harness = Harness::new(Config {
+            spawn_on_activate: true,
+            ..Default::default()
+        });
+        harness.activate();
+        assert!(harness.app.popup_shown());
+        let requests = harness.requests();
+        assert!(requests
+            .iter()
+            .any(|r| r.contains(".get_input_popup_surface(")));
+        // Still active, so the next Done doesn't make another.
+        harness.type_key(KEY);
+        assert!(!harness.app.popup_shown());
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.send(&input_method, "done", vec![]);
+        harness.roundtrip();
+        assert!(!harness.app.popup_shown());
+    }
+
+    #[test]
+    fn latencies_are_taken_once() {
+        let clock = ManualClock::new();
+        let mut start = Some(clock.now());
+        clock.advance(Duration::from_millis(16));
+        assert_eq!(
+            take_elapsed(&mut start, &clock),
+            Some(Duration::from_millis(16))
+        );
+        assert_eq!(take_elapsed(&mut start, &clock), None);
+    }
+
+    #[test]
+    fn first_frame_latency_is_from_popup_creation() {
+        let mut harness = Harness::new(Config::default());
+        let clock = Rc::new(ManualClock::new());
+        harness.app.clock = clock.clone();
+        harness.activate();
+        harness.type_key(KEY);
+        assert_eq!(harness.app.popup_created_at, Some(clock.now()));
+        clock.advance(Duration::from_millis(5));
+        harness.present();
+        assert_eq!(harness.app.popup_created_at, None);
+    }
+
+    #[test]
+    fn done_without_changes_commits_nothing() {
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.requests();
+        let input_method = harness.fake.newest("zwp_input_method_v2");
+        harness.fake.send(&input_method, "done", vec![]);
+        let commit = format!("{}.commit(", harness.app.input_method.id());
+        assert!(!harness.requests().iter().any(|r| r.starts_with(&commit)));
+        // But one that does is committed with its serial.
+        harness.fake.send(&input_method, "deactivate", vec![]);
+        harness.fake.send(&input_method, "done", vec![]);
+        assert!(harness.requests().contains(&format!("{commit}3)")));
+    }
+
+    #[test]
+    fn override_keymap_keeps_ours() {
+        use std::os::fd::AsRawFd;
+        let path = env::temp_dir().join(format!("sway-im-popup-override-{}", std::process::id()));
+        fs::write(&path, "xkb_keymap {};").unwrap();
+        let mut harness = Harness::new(Config {
+            keymap: Some(path.clone()),
+            override_keymap: true,
+            ..Default::default()
+        });
+        harness.activate();
+        let grab = harness.fake.newest("zwp_input_method_keyboard_grab_v2");
+        let (keymap, size) = load_keymap(&path).unwrap();
+        let args = vec![
+            Argument::Uint(1),
+            Argument::Fd(keymap.as_raw_fd()),
+            Argument::Uint(size),
+        ];
+        harness.fake.send(&grab, "keymap", args);
+        let requests = harness.requests();
+        assert!(!requests.iter().any(|r| r.contains(".keymap(")));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn each_popups_first_frame_is_marked() {
+        let mut harness = Harness::new(Config {
+            mark_first_frame: true,
+            dump_last_buffer: Some("unused".into()),
+            ..Default::default()
+        });
+        let first_pixel = |app: &App| {
+            let (_, data) = app.last_buffer.as_ref().unwrap();
+            <[u8; 4]>::try_from(&data[..4]).unwrap()
+        };
+        harness.activate();
+        harness.type_key(KEY);
+        assert_eq!(first_pixel(&harness.app), [255, 255, 255, 255]);
+        harness.present();
+        assert_eq!(first_pixel(&harness.app), draw::BACKGROUND);
+        // Hidden, then shown again: a new popup, with its own mark.
+        harness.type_key(KEY);
+        harness.type_key(KEY);
+        assert!(harness.app.frame > 1);
+        assert_eq!(first_pixel(&harness.app), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn scenarios_stop_at_the_first_failed_expectation() {
+        let path = env::temp_dir().join(format!("sway-im-popup-scenario-{}", std::process::id()));
+        let script = "activate\nkey space\nexpect popups 1\nkey space\nkey space\nexpect popups 1\nkey space\n";
+        fs::write(&path, script).unwrap();
+        let mut harness = Harness::new(Config {
+            scenario: Some(path.clone()),
+            ..Default::default()
+        });
+        fs::remove_file(&path).unwrap();
+        harness.roundtrip();
+        assert_eq!(
+            harness.app.scenario_failure.as_deref(),
+            Some("line 6: expected 1 popups, got 2")
+        );
+        assert!(harness.app.exit);
+        // The last key was never pressed.
+        assert_eq!(harness.app.toggles, 3);
+    }
+
+    #[test]
+    fn watch_file_changes_apply_to_the_current_popup() {
+        let path = env::temp_dir().join(format!("sway-im-popup-watch-{}", std::process::id()));
+        fs::write(&path, "fill-color = 00ff00\n").unwrap();
+        let mut harness = Harness::new(Config::default());
+        harness.activate();
+        harness.type_key(KEY);
+        assert_eq!(harness.app.popup_color, draw::FILL);
+        harness.app.check_watch_file(&path);
+        assert_eq!(harness.app.popup_color, [0, 255, 0, 255]);
+        // A bad file keeps what we had.
+        fs::write(&path, "fill-color = green\n").unwrap();
+        harness.app.watched_mtime = None;
+        harness.app.check_watch_file(&path);
+        assert_eq!(harness.app.popup_color, [0, 255, 0, 255]);
+        assert_eq!(warnings().len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keys_say_what_became_of_them() {
+        let mut harness = Harness::new(Config {
+            toggle_key: Some("space".into()),
+            ..Default::default()
+        });
+        harness.activate();
+        // Events for anything made through this queue go unread, but these
+        // keys only need the popup created.
+        let queue = harness.conn.new_event_queue();
+        let qh = queue.handle();
+        let mut press = |key| {
+            let pressed = WEnum::Value(wl_keyboard::KeyState::Pressed);
+            harness.app.handle_key(0, key, pressed, &qh)
+        };
+        assert_eq!(press(KEY), KeyAction::ForwardedOnly);
+        assert_eq!(press(57), KeyAction::ToggledOn);
+        assert_eq!(press(57), KeyAction::ToggledOff);
+    }
+
+    #[test]
+    fn click_picks_candidate_band() {
+        assert_eq!(candidate_at(0.0, 100, 4), 0);
+        assert_eq!(candidate_at(24.9, 100, 4), 0);
+        assert_eq!(candidate_at(25.0, 100, 4), 1);
+        assert_eq!(candidate_at(99.5, 100, 4), 3);
+        // Pointer coordinates can stray just outside the surface.
+        assert_eq!(candidate_at(-1.0, 100, 4), 0);
+        assert_eq!(candidate_at(100.0, 100, 4), 3);
+    }
+
+    #[test]
+    fn clicking_the_popup_commits_a_candidate() {
+        let mut harness = Harness::new(Config {
+            click_candidates: Some("a,b".into()),
+            ..Default::default()
+        });
+        let seat = harness.fake.newest("wl_seat");
+        harness
+            .fake
+            .send(&seat, "capabilities", vec![Argument::Uint(1)]);
+        harness.activate();
+        harness.type_key(KEY);
+        harness.requests();
+        let pointer = harness.fake.newest("wl_pointer");
+        let surface = harness.fake.newest("wl_surface");
+        let fixed = |v: f64| Argument::Fixed((v * 256.0) as i32);
+        let enter = vec![
+            Argument::Uint(0),
+            Argument::Object(surface),
+            fixed(1.0),
+            fixed(1.0),
+        ];
+        harness.fake.send(&pointer, "enter", enter);
+        let y = HEIGHT as f64 * 0.75;
+        let motion = vec![Argument::Uint(0), fixed(1.0), fixed(y)];
+        harness.fake.send(&pointer, "motion", motion);
+        let button = [0, 0, 0x110, 1].map(Argument::Uint).to_vec();
+        harness.fake.send(&pointer, "button", button);
+        let input_method = harness.app.input_method.id();
+        assert!(harness
+            .requests()
+            .contains(&format!(r#"{input_method}.commit_string("b")"#)));
+    }
+
+    #[test]
+    fn ime_only_commits_what_keys_type() {
+        use std::os::fd::AsRawFd;
+        let mut harness = Harness::new(Config {
+            ime_only: true,
+            ..Default::default()
+        });
+        harness.activate();
+        // Without a keymap, keys type nothing.
+        harness.type_key(KEY);
+        assert_eq!(warnings().len(), 1);
+        let path = env::temp_dir().join(format!("sway-im-popup-ime-only-{}", std::process::id()));
+        fs::write(&path, typing::keymap_text("us")).unwrap();
+        let (keymap, size) = load_keymap(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let grab = harness.fake.newest("zwp_input_method_keyboard_grab_v2");
+        let args = vec![
+            Argument::Uint(1),
+            Argument::Fd(keymap.as_raw_fd()),
+            Argument::Uint(size),
+        ];
+        harness.fake.send(&grab, "keymap", args);
+        harness.requests();
+        harness.type_key(KEY);
+        // With Shift held.
+        let modifiers = [0, 1, 0, 0, 0].map(Argument::Uint).to_vec();
+        harness.fake.send(&grab, "modifiers", modifiers);
+        harness.type_key(KEY);
+        let input_method = harness.app.input_method.id();
+        let typed: Vec<String> = harness
+            .requests()
+            .into_iter()
+            .filter(|r| r.contains(".commit_string("))
+            .collect();
+        assert_eq!(
+            typed,
+            [
+                format!(r#"{input_method}.commit_string("a")"#),
+                format!(r#"{input_method}.commit_string("A")"#),
+            ]
+        );
+    }
+
+    #[test]
+    fn pool_stats_show_growth_and_use() {
+        assert_eq!(
+            pool_stats_line(24000, 12000, 2, 24000, 1),
+            "24000 bytes (+12000 since last logged), 2 buffers using 24000 bytes, 1 held by the compositor"
+        );
+        assert!(pool_stats_line(12000, -12000, 1, 12000, 0).contains("(-12000 since"));
+        assert!(pool_stats_line(12000, 0, 1, 12000, 0).contains("(+0 since"));
+    }
+
+    #[test]
+    fn pool_stats_are_logged_every_n_frames() {
+        let mut harness = Harness::new(Config {
+            pool_stats: "2".parse().ok(),
+            ..Default::default()
+        });
+        harness.app.pool_logged_len = 0;
+        harness.activate();
+        harness.type_key(KEY);
+        assert_eq!(harness.app.pool_logged_len, 0);
+        harness.present();
+        assert_eq!(harness.app.frame, 2);
+        assert_eq!(harness.app.pool_logged_len, harness.app.shm_pool.len());
+    }
+
+    #[test]
+    fn mirror_shows_the_same_bytes_as_the_popup() {
+        let mut harness = Harness::new(Config {
+            mirror: true,
+            ..Default::default()
+        });
+        let xdg_surface = harness.fake.newest("xdg_surface");
+        harness
+            .fake
+            .send(&xdg_surface, "configure", vec![Argument::Uint(1)]);
+        harness.activate();
+        harness.type_key(KEY);
+        assert_eq!(harness.app.frame, 1);
+
+        let app = &mut harness.app;
+        let size = app.layout.stride * app.layout.height;
+        let popup = app.shm_pool.raw_data_mut(&app.buffer.slot())[..size].to_vec();
+        let mirror = app.mirror.as_ref().unwrap().buffer().unwrap();
+        assert_ne!(mirror.wl_buffer(), app.buffer.wl_buffer());
+        let slot = mirror.slot();
+        assert_eq!(app.shm_pool.raw_data_mut(&slot)[..size], popup);
+    }
+
+    #[test]
+    fn warmup_keys_are_counted_off() {
+        let mut left = 2;
+        assert!(warming_up(&mut left));
+        assert!(warming_up(&mut left));
+        assert!(!warming_up(&mut left));
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn warmup_keys_are_forwarded_without_toggling() {
+        let mut harness = Harness::new(Config {
+            warmup_keys: 2,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.requests();
+        for _ in 0..2 {
+            harness.type_key(KEY);
+            assert!(!harness.app.popup_shown());
+        }
+        let forwarded = harness.requests();
+        let keys = forwarded.iter().filter(|r| r.contains(".key("));
+        assert_eq!(keys.count(), 4, "{forwarded:?}");
+        harness.type_key(KEY);
+        assert!(harness.app.popup_shown());
+    }
+
+    #[test]
+    fn check_keymap_warns_only_on_a_mismatch() {
+        let (us, de) = (typing::keymap_text("us"), typing::keymap_text("de"));
+        warnings();
+        check_keymap(&us, &us);
+        assert_eq!(warnings(), Vec::<String>::new());
+        check_keymap(&us, &de);
+        let warned = warnings();
+        assert_eq!(warned.len(), 1);
+        assert!(
+            warned[0].starts_with("--keymap doesn't match"),
+            "{warned:?}"
+        );
+    }
+
+    #[test]
+    fn sync_driven_draw_waits_on_display_syncs() {
+        let mut harness = Harness::new(Config {
+            sync_driven_draw: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.requests();
+        harness.type_key(KEY);
+        let requests = harness.requests();
+        assert!(
+            !requests.iter().any(|r| r.contains(".frame(")),
+            "{requests:?}"
+        );
+        // The fake answers syncs itself, so frames come without presenting.
+        let frame = harness.app.frame;
+        harness.roundtrip();
+        assert!(harness.app.frame > frame);
+        // And stop with the popup.
+        harness.type_key(KEY);
+        harness.roundtrip();
+        let frame = harness.app.frame;
+        harness.roundtrip();
+        assert_eq!(harness.app.frame, frame);
+    }
+
+    #[test]
+    fn log_requests_gives_a_popups_requests_in_order() {
+        let mut harness = Harness::new(Config {
+            log_requests: true,
+            ..Default::default()
+        });
+        harness.activate();
+        LOGGED_REQUESTS.take();
+        harness.type_key(KEY);
+        let logged = LOGGED_REQUESTS.take();
+        let names: Vec<&str> = logged
+            .iter()
+            .map(|line| line.split(['.', '(']).nth(1).unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "get_input_popup_surface",
+                "attach",
+                "damage_buffer",
+                "frame",
+                "commit",
+                "key",
+                "key",
+            ],
+            "{logged:?}"
+        );
+    }
+
+    #[test]
+    fn stress_toggles_once_per_turn_until_frames() {
+        let mut harness = Harness::new(Config {
+            stress: true,
+            frames: Some(3),
+            ..Default::default()
+        });
+        harness.activate();
+        let mut toggles = harness.app.toggles;
+        assert!(toggles > 0);
+        while !harness.app.exit {
+            assert!(toggles < 10, "never reached --frames");
+            harness.roundtrip();
+            harness.app.check_frame_limit();
+            toggles += 1;
+            assert_eq!(harness.app.toggles, toggles);
+        }
+        assert_eq!(harness.app.frame, 3);
+        harness.roundtrip();
+        assert_eq!(harness.app.toggles, toggles);
+        assert!(!harness.app.stressing);
+    }
+
+    #[test]
+    fn shared_buffer_is_attached_to_every_surface_once_released() {
+        let mut harness = Harness::new(Config {
+            workaround: true,
+            shared_buffer: true,
+            ..Default::default()
+        });
+        harness.activate();
+        harness.requests();
+        let attached = |requests: &[String]| -> Vec<(String, String)> {
+            requests
+                .iter()
+                .filter_map(|r| r.split_once(".attach("))
+                .map(|(surface, args)| (surface.into(), args.into()))
+                .collect()
+        };
+        harness.type_key(KEY);
+        let first = attached(&harness.requests());
+        assert_eq!(first.len(), 1, "{first:?}");
+        harness.type_key(KEY);
+        // The compositor still holds the buffer, so the new surface waits.
+        harness.type_key(KEY);
+        let requests = harness.requests();
+        assert!(requests.iter().any(|r| r.contains(".create_surface(")));
+        assert_eq!(attached(&requests), []);
+        assert_eq!(harness.app.frame, 1);
+
+        let buffer = harness.fake.newest("wl_buffer");
+        harness.fake.send(&buffer, "release", vec![]);
+        harness.roundtrip();
+        let second = attached(&harness.requests());
+        assert_eq!(second.len(), 1, "{second:?}");
+        assert_ne!(first[0].0, second[0].0);
+        assert_eq!(first[0].1, second[0].1);
+        assert_eq!(harness.app.frame, 2);
+    }
+
+    #[test]
+    fn expected_frames_follow_refresh() {
+        assert_eq!(frames_in(Duration::from_secs(2), DEFAULT_REFRESH), 120);
+        assert_eq!(frames_in(Duration::from_millis(500), 144_000), 72);
+        assert_eq!(frames_in(Duration::ZERO, DEFAULT_REFRESH), 0);
     }
 }
@@ -0,0 +1,152 @@
+// `--mirror` also shows whatever the popup shows in an ordinary window, so
+// the two can be watched side by side. If only the popup duplicates, our
+// buffers are fine and the bug is in how the compositor treats popups.
+
+use smithay_client_toolkit::{
+    reexports::protocols::xdg::shell::client::{
+        xdg_surface::{self, XdgSurface},
+        xdg_toplevel::{self, XdgToplevel},
+        xdg_wm_base::{self, XdgWmBase},
+    },
+    shm::slot::{Buffer, SlotPool},
+};
+use wayland_client::{
+    protocol::{wl_compositor::WlCompositor, wl_surface::WlSurface},
+    Connection, Dispatch, QueueHandle,
+};
+
+use crate::{create_buffer, draw::Layout, App};
+
+// The mirror window, destroyed when dropped.
+pub struct Mirror {
+    wm_base: XdgWmBase,
+    surface: WlSurface,
+    xdg_surface: XdgSurface,
+    toplevel: XdgToplevel,
+    buffer: Option<Buffer>,
+    // What to show once the window is configured, until then.
+    pending: Option<(Layout, Vec<u8>)>,
+    configured: bool,
+}
+
+impl Mirror {
+    pub fn new(wm_base: XdgWmBase, compositor: &WlCompositor, qh: &QueueHandle<App>) -> Self {
+        let surface = compositor.create_surface(qh, ());
+        let xdg_surface = wm_base.get_xdg_surface(&surface, qh, ());
+        let toplevel = xdg_surface.get_toplevel(qh, ());
+        toplevel.set_title("sway-im-popup mirror".into());
+        // Asks for the first configure.
+        surface.commit();
+        Mirror {
+            wm_base,
+            surface,
+            xdg_surface,
+            toplevel,
+            buffer: None,
+            pending: None,
+            configured: false,
+        }
+    }
+
+    // Shows a copy of the popup's latest buffer, in a buffer of our own.
+    pub fn show(&mut self, pool: &mut SlotPool, layout: Layout, data: Vec<u8>) {
+        if !self.configured {
+            self.pending = Some((layout, data));
+            return;
+        }
+        let reusable = self.buffer.as_ref().filter(|buffer| {
+            buffer.height() as usize == layout.height && buffer.stride() as usize == layout.stride
+        });
+        let canvas = match reusable.and_then(|buffer| buffer.canvas(pool)) {
+            Some(canvas) => canvas,
+            None => {
+                let (buffer, canvas) = create_buffer(pool, layout);
+                self.buffer = Some(buffer);
+                canvas
+            }
+        };
+        // Either canvas may run past the end of the image.
+        let len = layout.stride * layout.height;
+        canvas[..len].copy_from_slice(&data[..len]);
+        let buffer = self.buffer.as_ref().unwrap();
+        if let Err(e) = buffer.attach_to(&self.surface) {
+            warning!("can't attach the mirror's buffer, skipping a frame: {e}");
+            return;
+        }
+        self.surface
+            .damage_buffer(0, 0, layout.width as i32, layout.height as i32);
+        self.surface.commit();
+    }
+
+    #[cfg(test)]
+    pub fn buffer(&self) -> Option<&Buffer> {
+        self.buffer.as_ref()
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        self.toplevel.destroy();
+        self.xdg_surface.destroy();
+        self.surface.destroy();
+        self.wm_base.destroy();
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for App {
+    fn event(
+        _: &mut Self,
+        wm_base: &XdgWmBase,
+        event: xdg_wm_base::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            wm_base.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, ()> for App {
+    fn event(
+        app: &mut Self,
+        xdg_surface: &XdgSurface,
+        event: xdg_surface::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let _span = app.handling("xdg_surface");
+        let xdg_surface::Event::Configure { serial } = event else {
+            return;
+        };
+        xdg_surface.ack_configure(serial);
+        if let Some(mirror) = &mut app.mirror {
+            mirror.configured = true;
+            if let Some((layout, data)) = mirror.pending.take() {
+                mirror.show(&mut app.shm_pool, layout, data);
+            } else {
+                // Each configure needs a commit in reply.
+                mirror.surface.commit();
+            }
+        }
+    }
+}
+
+impl Dispatch<XdgToplevel, ()> for App {
+    fn event(
+        app: &mut Self,
+        _: &XdgToplevel,
+        event: xdg_toplevel::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let _span = app.handling("xdg_toplevel");
+        if let xdg_toplevel::Event::Close = event {
+            println!("Mirror window closed");
+            app.mirror = None;
+        }
+    }
+}
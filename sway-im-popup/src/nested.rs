@@ -0,0 +1,113 @@
+// `--launch-nested` runs a private sway inside the current session, so the
+// whole reproduction is one command. sway's output goes to sway.log in a
+// temporary directory, which is printed at startup.
+
+use std::{
+    env,
+    error::Error,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long to wait for the nested sway to create its socket.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The nested sway, killed when dropped.
+pub struct Nested {
+    child: Child,
+}
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Starts sway and points WAYLAND_DISPLAY at it.
+pub fn launch() -> Result<Nested, Box<dyn Error>> {
+    let sway = find_on_path("sway").ok_or("--launch-nested: sway not found on PATH")?;
+    let dir = env::temp_dir().join(format!("sway-im-popup-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let display_file = dir.join("display");
+    let _ = fs::remove_file(&display_file);
+    fs::write(dir.join("config"), config(&display_file))?;
+    println!(
+        "Launching nested sway, logging to {}",
+        dir.join("sway.log").display()
+    );
+
+    let mut command = nested_command(&sway, &dir);
+    command.stderr(File::create(dir.join("sway.log"))?);
+    let mut nested = Nested {
+        child: command.spawn()?,
+    };
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    let display = loop {
+        if let Ok(display) = fs::read_to_string(&display_file) {
+            if !display.trim().is_empty() {
+                break display.trim().to_owned();
+            }
+        }
+        if let Some(status) = nested.child.try_wait()? {
+            return Err(format!("nested sway exited early ({status})").into());
+        }
+        if Instant::now() > deadline {
+            return Err("nested sway didn't start in time".into());
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    println!("Nested sway is on WAYLAND_DISPLAY={display}; start a text input client there");
+    env::set_var("WAYLAND_DISPLAY", display);
+    Ok(nested)
+}
+
+// A bare config whose only job is to tell us the socket name once it's up.
+fn config(display_file: &Path) -> String {
+    format!(
+        "exec sh -c 'echo $WAYLAND_DISPLAY > {}'\n",
+        display_file.display()
+    )
+}
+
+fn nested_command(sway: &Path, dir: &Path) -> Command {
+    let mut command = Command::new(sway);
+    command
+        .arg("--config")
+        .arg(dir.join("config"))
+        // Run as a window in the current session, whatever that is.
+        .env("WLR_BACKENDS", "wayland")
+        .env_remove("SWAYSOCK");
+    command
+}
+
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn command_line() {
+        let command = nested_command(Path::new("/usr/bin/sway"), Path::new("/tmp/x"));
+        assert_eq!(command.get_program(), "/usr/bin/sway");
+        let args: Vec<&OsStr> = command.get_args().collect();
+        assert_eq!(args, ["--config", "/tmp/x/config"]);
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("WLR_BACKENDS"), Some(OsStr::new("wayland")))));
+        assert!(envs.contains(&(OsStr::new("SWAYSOCK"), None)));
+        assert_eq!(
+            config(Path::new("/tmp/x/display")),
+            "exec sh -c 'echo $WAYLAND_DISPLAY > /tmp/x/display'\n"
+        );
+    }
+}
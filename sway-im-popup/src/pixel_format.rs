@@ -0,0 +1,103 @@
+// `--pixel-format-test` checks that our buffers are Argb8888 as wl_shm
+// defines it: each pixel a little-endian 0xAARRGGBB. It fills a wl_shm
+// buffer with known values, reads it back, and prints PASS or FAIL.
+// Nothing is shown, so the compositor is only needed for the shm pool.
+
+use std::{error::Error, process};
+
+use smithay_client_toolkit::shm::slot::SlotPool;
+use wayland_client::{globals::registry_queue_init, protocol::wl_shm::WlShm, Connection};
+
+use crate::{bind, create_buffer, draw, draw::Layout, App, Provider};
+
+pub fn main(conn: &Connection) -> Result<(), Box<dyn Error>> {
+    let (globals, event_queue) = registry_queue_init::<App>(conn)?;
+    let shm: WlShm = bind(&globals, &event_queue.handle(), 1..=1)?;
+    let mut pool = SlotPool::new(1, &Provider(shm))?;
+    let failures = failures(&mut pool);
+    if !failures.is_empty() {
+        println!("FAIL: {}", failures.join("; "));
+        process::exit(1);
+    }
+    println!("PASS");
+    Ok(())
+}
+
+fn failures(pool: &mut SlotPool) -> Vec<String> {
+    let mut failures = Vec::new();
+    for (name, bytes, expected) in [
+        ("fill", draw::FILL, 0xffff0000),
+        ("background", draw::BACKGROUND, 0xff0000ff),
+    ] {
+        let actual = u32::from_le_bytes(bytes);
+        if actual != expected {
+            failures.push(format!(
+                "{name} is {actual:#010x}, expected {expected:#010x}"
+            ));
+        }
+    }
+
+    // Every pixel, and every channel within it, has a different value.
+    let layout = Layout::new(16, 16, None);
+    let (buffer, canvas) = create_buffer(pool, layout);
+    for (i, pixel) in canvas.chunks_exact_mut(4).enumerate() {
+        pixel.copy_from_slice(&gradient(i).to_le_bytes());
+    }
+    // Read back from the pool, as the compositor will, and see the pixels
+    // through to_json as --dump-last-buffer and --compare do.
+    let data = buffer.canvas(pool).expect("buffer is ours");
+    let actual = draw::to_json(data, layout);
+    let expected = expected_json(layout);
+    if actual != expected {
+        failures.push(format!(
+            "read back as {}..., expected {}...",
+            &actual[..40],
+            &expected[..40]
+        ));
+    }
+    failures
+}
+
+fn gradient(i: usize) -> u32 {
+    let i = i as u32 % 256;
+    let (a, r, g, b) = (255 - i, i, (i * 3) % 256, (i * 7) % 256);
+    a << 24 | r << 16 | g << 8 | b
+}
+
+// What to_json should produce, derived from the pixel values alone.
+fn expected_json(layout: Layout) -> String {
+    let rows: Vec<String> = (0..layout.height)
+        .map(|y| {
+            let pixels: Vec<String> = (0..layout.width)
+                .map(|x| {
+                    let [a, r, g, b] = gradient(y * layout.width + x).to_be_bytes();
+                    format!("[{r},{g},{b},{a}]")
+                })
+                .collect();
+            format!("[{}]", pixels.join(","))
+        })
+        .collect();
+    format!("[\n{}\n]\n", rows.join(",\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_compositor::FakeCompositor;
+
+    #[test]
+    fn shm_buffers_read_back_as_argb() {
+        let (mut fake, conn) = FakeCompositor::new();
+        let (globals, event_queue) = fake.serve(|| registry_queue_init::<App>(&conn)).unwrap();
+        let shm: WlShm = bind(&globals, &event_queue.handle(), 1..=1).unwrap();
+        let mut pool = SlotPool::new(1, &Provider(shm)).unwrap();
+        assert_eq!(failures(&mut pool), Vec::<String>::new());
+    }
+
+    #[test]
+    fn expected_json_is_argb() {
+        // Pixel 1: a = 254, r = 1, g = 3, b = 7.
+        let json = expected_json(Layout::new(2, 1, None));
+        assert!(json.starts_with("[\n[[0,0,0,255],[1,3,7,254]]"), "{json}");
+    }
+}
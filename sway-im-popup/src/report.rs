@@ -0,0 +1,164 @@
+// `--report FILE` summarizes the run as JSON, to attach to a bug report.
+
+use crate::config::Config;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    // Interface name and bound version, from the latest connection.
+    pub versions: Vec<(&'static str, u32)>,
+    pub frames: usize,
+    pub commits: usize,
+    pub popups_opened: usize,
+    pub reconnects: usize,
+    pub disconnected: bool,
+    pub protocol_errors: Vec<String>,
+    // Why we exited, if it wasn't a clean shutdown.
+    pub error: Option<String>,
+}
+
+impl Report {
+    pub fn to_json(&self, config: &Config) -> String {
+        let versions: Vec<String> = self
+            .versions
+            .iter()
+            .map(|(interface, version)| format!("{}: {version}", quote(interface)))
+            .collect();
+        let flags: Vec<String> = config
+            .to_flags()
+            .iter()
+            .map(|(flag, value)| format!("{}: {}", quote(flag), quote(value)))
+            .collect();
+        let protocol_errors: Vec<String> = self.protocol_errors.iter().map(|e| quote(e)).collect();
+        let fields = [
+            ("config", format!("{{{}}}", flags.join(", "))),
+            ("versions", format!("{{{}}}", versions.join(", "))),
+            ("frames", self.frames.to_string()),
+            ("commits", self.commits.to_string()),
+            ("popups_opened", self.popups_opened.to_string()),
+            ("reconnects", self.reconnects.to_string()),
+            ("disconnected", self.disconnected.to_string()),
+            (
+                "protocol_errors",
+                format!("[{}]", protocol_errors.join(", ")),
+            ),
+            ("error", self.error.as_deref().map_or("null".into(), quote)),
+        ];
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|(key, value)| format!("  {}: {value}", quote(key)))
+            .collect();
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+}
+
+// A JSON string literal.
+pub fn quote(s: &str) -> String {
+    let mut result = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Just enough of a JSON parser to check the report is well-formed: skips
+    // one value, returning what follows it.
+    fn skip_value(s: &str) -> Option<&str> {
+        let s = s.trim_start();
+        let (open, close) = match s.chars().next()? {
+            '{' => ('{', '}'),
+            '[' => ('[', ']'),
+            '"' => {
+                let mut chars = s.char_indices().skip(1);
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next()?;
+                        }
+                        '"' => return Some(&s[i + 1..]),
+                        c if c.is_control() => return None,
+                        _ => {}
+                    }
+                }
+                return None;
+            }
+            _ => {
+                let end = s
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-')
+                    .unwrap_or(s.len());
+                let word = &s[..end];
+                let ok = ["true", "false", "null"].contains(&word) || word.parse::<f64>().is_ok();
+                return ok.then_some(&s[end..]);
+            }
+        };
+        let mut rest = s[1..].trim_start();
+        if let Some(rest) = rest.strip_prefix(close) {
+            return Some(rest);
+        }
+        loop {
+            if open == '{' {
+                if !rest.starts_with('"') {
+                    return None;
+                }
+                rest = skip_value(rest)?.trim_start().strip_prefix(':')?;
+            }
+            rest = skip_value(rest)?.trim_start();
+            match rest.chars().next()? {
+                ',' => rest = rest[1..].trim_start(),
+                c if c == close => return Some(&rest[1..]),
+                _ => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn json_has_every_key() {
+        let report = Report {
+            versions: vec![("wl_seat", 9)],
+            protocol_errors: vec!["bad \"surface\"".into()],
+            ..Default::default()
+        };
+        let config = Config {
+            exit_key: Some("esc".into()),
+            ..Default::default()
+        };
+        let json = report.to_json(&config);
+        assert_eq!(skip_value(&json).map(str::trim), Some(""), "{json}");
+        assert_eq!(skip_value(r#"{"a": [1, "b\\"c"], "d": nul}"#), None);
+        for key in [
+            "config",
+            "versions",
+            "frames",
+            "commits",
+            "popups_opened",
+            "reconnects",
+            "disconnected",
+            "protocol_errors",
+            "error",
+        ] {
+            assert!(
+                json.contains(&format!("\n  \"{key}\": ")),
+                "no {key} in {json}"
+            );
+        }
+        assert!(json.contains(r#""exit-key": "esc""#), "{json}");
+        assert!(json.contains(r#""versions": {"wl_seat": 9}"#));
+        assert!(json.contains(r#""protocol_errors": ["bad \"surface\""]"#));
+        assert!(json.contains(r#""error": null"#));
+    }
+
+    #[test]
+    fn quotes_json_strings() {
+        assert_eq!(quote("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+}
@@ -0,0 +1,89 @@
+// `--scenario FILE` drives the demo from a script instead of the keyboard,
+// so a reproduction can be replayed exactly. One step per line:
+//
+//   activate          act as if the compositor activated us
+//   key KEY           press and release KEY (a name or keycode)
+//   wait DURATION     e.g. 100ms or 2s
+//   deactivate        act as if the compositor deactivated us
+//   expect popups N   fail unless N popups have been opened so far
+//
+// Blank lines and lines starting with # are ignored. When the script ends,
+// we exit.
+
+use std::time::Duration;
+
+use crate::keys::ScancodeMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Activate,
+    Deactivate,
+    Key(u32),
+    Wait(Duration),
+    ExpectPopups(usize),
+}
+
+// Each step with its line number, for error messages.
+pub fn parse(text: &str, keys: &ScancodeMap) -> Result<Vec<(usize, Step)>, String> {
+    let mut steps = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let step = match words[..] {
+            [] => continue,
+            [comment, ..] if comment.starts_with('#') => continue,
+            ["activate"] => Ok(Step::Activate),
+            ["deactivate"] => Ok(Step::Deactivate),
+            ["key", key] => keys.resolve(key).map(Step::Key),
+            ["wait", duration] => parse_duration(duration).map(Step::Wait),
+            ["expect", "popups", n] => n
+                .parse()
+                .map(Step::ExpectPopups)
+                .map_err(|_| format!("bad count {n}")),
+            _ => Err(format!("unknown step {:?}", line.trim())),
+        }
+        .map_err(|e| format!("line {}: {e}", i + 1))?;
+        steps.push((i + 1, step));
+    }
+    Ok(steps)
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let parsed = if let Some(ms) = s.strip_suffix("ms") {
+        ms.parse().map(Duration::from_millis)
+    } else if let Some(secs) = s.strip_suffix('s') {
+        secs.parse().map(Duration::from_secs)
+    } else {
+        return Err(format!("expected a duration like 100ms or 2s, got {s}"));
+    };
+    parsed.map_err(|_| format!("bad duration {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_steps() {
+        let text = "activate\n# comment\n\nkey space\nwait 100ms\nexpect popups 1\n";
+        assert_eq!(
+            parse(text, &ScancodeMap::default()),
+            Ok(vec![
+                (1, Step::Activate),
+                (4, Step::Key(57)),
+                (5, Step::Wait(Duration::from_millis(100))),
+                (6, Step::ExpectPopups(1)),
+            ])
+        );
+    }
+
+    #[test]
+    fn reports_bad_lines() {
+        let keys = ScancodeMap::default();
+        assert_eq!(
+            parse("activate\njump\n", &keys),
+            Err("line 2: unknown step \"jump\"".into())
+        );
+        assert!(parse("wait 2", &keys).is_err());
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,87 @@
+// A model of the text around the cursor, to check that what we commit is
+// what the app ends up with.
+
+use std::fmt;
+
+// Offsets are in bytes, like in the protocol.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SurroundingText {
+    pub text: String,
+    pub cursor: usize,
+    pub anchor: usize,
+}
+
+impl SurroundingText {
+    // As sent by the compositor. Out-of-range offsets are clamped, so a
+    // confused compositor can't crash us.
+    pub fn new(text: String, cursor: u32, anchor: u32) -> Self {
+        let clamp = |offset: u32| {
+            let mut offset = (offset as usize).min(text.len());
+            while !text.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            offset
+        };
+        let (cursor, anchor) = (clamp(cursor), clamp(anchor));
+        SurroundingText {
+            text,
+            cursor,
+            anchor,
+        }
+    }
+
+    // What the app should do with a commit_string: replace the selection (if
+    // any) and put the cursor after the new text.
+    pub fn commit(&mut self, committed: &str) {
+        let start = self.cursor.min(self.anchor);
+        let end = self.cursor.max(self.anchor);
+        self.text.replace_range(start..end, committed);
+        self.cursor = start + committed.len();
+        self.anchor = self.cursor;
+    }
+}
+
+// The text with the cursor marked by `|`, and the selection (if any) by `[]`.
+impl fmt::Display for SurroundingText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (start, end) = (self.cursor.min(self.anchor), self.cursor.max(self.anchor));
+        let selected = &self.text[start..end];
+        let marked = if start == end {
+            "|".to_owned()
+        } else if self.cursor == start {
+            format!("[|{selected}]")
+        } else {
+            format!("[{selected}|]")
+        };
+        let (before, after) = (&self.text[..start], &self.text[end..]);
+        write!(f, "{:?}", format!("{before}{marked}{after}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commits_accumulate() {
+        let mut model = SurroundingText::default();
+        model.commit("ab");
+        model.commit("c");
+        assert_eq!(model, SurroundingText::new("abc".into(), 3, 3));
+        assert_eq!(model.to_string(), r#""abc|""#);
+    }
+
+    #[test]
+    fn commit_replaces_selection() {
+        let mut model = SurroundingText::new("hello".into(), 4, 1);
+        assert_eq!(model.to_string(), r#""h[ell|]o""#);
+        model.commit("i");
+        assert_eq!(model.to_string(), r#""hi|o""#);
+    }
+
+    #[test]
+    fn offsets_are_clamped_to_char_boundaries() {
+        let model = SurroundingText::new("é".into(), 1, 99);
+        assert_eq!((model.cursor, model.anchor), (0, 2));
+    }
+}
@@ -0,0 +1,54 @@
+// `--trace-timing` logs how long each event handler takes, to see whether
+// any (especially drawing) is slow enough to matter.
+
+use std::{
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+
+// Logs the time from creation to drop, if enabled.
+pub struct Span {
+    name: &'static str,
+    start: Option<(Rc<dyn Clock>, Instant)>,
+}
+
+impl Span {
+    pub fn new(enabled: bool, name: &'static str, clock: &Rc<dyn Clock>) -> Self {
+        Span {
+            name,
+            start: enabled.then(|| (clock.clone(), clock.now())),
+        }
+    }
+
+    fn elapsed(&self) -> Option<Duration> {
+        let (clock, start) = self.start.as_ref()?;
+        Some(clock.now() - *start)
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(elapsed) = self.elapsed() {
+            println!("{} handled in {elapsed:?}", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn spans_time_with_the_given_clock() {
+        let manual = Rc::new(ManualClock::new());
+        let clock: Rc<dyn Clock> = manual.clone();
+        let span = Span::new(true, "test", &clock);
+        assert_eq!(span.elapsed(), Some(Duration::ZERO));
+        manual.advance(Duration::from_millis(3));
+        assert_eq!(span.elapsed(), Some(Duration::from_millis(3)));
+        assert_eq!(Span::new(false, "test", &clock).elapsed(), None);
+    }
+}
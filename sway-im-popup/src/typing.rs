@@ -0,0 +1,77 @@
+// `--ime-only` commits what each key would have typed, which depends on the
+// user's layout and modifiers. So we follow the keymap and modifiers the grab
+// sends, as the app would with its own keyboard.
+
+use xkbcommon::xkb;
+
+pub struct Typing(xkb::State);
+
+impl Typing {
+    pub fn new(keymap: &str) -> Result<Self, String> {
+        let keymap = compile(keymap, "the grab's")?;
+        Ok(Typing(xkb::State::new(&keymap)))
+    }
+
+    // As sent with the grab's modifiers event.
+    pub fn set_modifiers(&mut self, depressed: u32, latched: u32, locked: u32, group: u32) {
+        self.0.update_mask(depressed, latched, locked, 0, 0, group);
+    }
+
+    // The text an evdev key types, if it types any. Keys like Enter and
+    // Backspace "type" control characters, which aren't text to commit.
+    pub fn text(&self, key: u32) -> Option<String> {
+        // xkb keycodes are evdev ones plus 8.
+        let text = self.0.key_get_utf8(xkb::Keycode::new(key + 8));
+        (!text.is_empty() && !text.chars().any(char::is_control)).then_some(text)
+    }
+}
+
+// Compiles a keymap as sent with a keyboard (or the grab), describing it as
+// `what` if that fails.
+pub fn compile(text: &str, what: &str) -> Result<xkb::Keymap, String> {
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    // A wl_keyboard keymap is a NUL-terminated string.
+    let text = text.trim_end_matches('\0').to_owned();
+    xkb::Keymap::new_from_string(
+        &context,
+        text,
+        xkb::KEYMAP_FORMAT_TEXT_V1,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    )
+    .ok_or_else(|| format!("can't compile {what} keymap"))
+}
+
+// A keymap for `layout`, as the compositor would send it.
+#[cfg(test)]
+pub fn keymap_text(layout: &str) -> String {
+    let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+    let keymap = xkb::Keymap::new_from_names(
+        &context,
+        "",
+        "",
+        layout,
+        "",
+        None,
+        xkb::KEYMAP_COMPILE_NO_FLAGS,
+    )
+    .expect("keymap");
+    keymap.get_as_string(xkb::KEYMAP_FORMAT_TEXT_V1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_layout_and_modifiers() {
+        let (a, y, enter) = (30, 21, 28);
+        let mut us = Typing::new(&keymap_text("us")).unwrap();
+        assert_eq!(us.text(a).as_deref(), Some("a"));
+        assert_eq!(us.text(enter), None);
+        // Shift is the first modifier in xkb's standard ones.
+        us.set_modifiers(1, 0, 0, 0);
+        assert_eq!(us.text(a).as_deref(), Some("A"));
+        let de = Typing::new(&keymap_text("de")).unwrap();
+        assert_eq!(de.text(y).as_deref(), Some("z"));
+    }
+}